@@ -0,0 +1,466 @@
+use std::alloc::Layout;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::free_list::{self, FreeList};
+use error::Error;
+use memfd::MemFd;
+use mmap::{page_size, MmapMut};
+use sealing::{Seal, SealsHashSet};
+use sync::{SharedMutex, SharedMutexGuard};
+use OpenOptions;
+
+const MAGIC: u64 = 0x6d65_6d66_6461_726e; // "memfdarn"
+const LEN_OFFSET: usize = 8;
+const FREE_HEAD_OFFSET: usize = 16;
+const MUTEX_OFFSET: usize = 24;
+const HEADER_SIZE: u64 = 128;
+
+const _: () = assert!(MUTEX_OFFSET + mem::size_of::<SharedMutex>() <= HEADER_SIZE as usize);
+
+/// The position of an allocation inside a `SharedArena`.
+///
+/// Offsets are meaningful in every process that maps the arena and can be passed between them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Offset(u64);
+
+impl Offset {
+    /// Creates an offset from its raw value, e.g. one received from another process.
+    pub fn from_raw(raw: u64) -> Offset {
+        Offset(raw)
+    }
+
+    /// The raw value of this offset.
+    pub fn as_raw(self) -> u64 {
+        self.0
+    }
+}
+
+/// A variable-sized object allocator over a growable memfd shared between processes.
+///
+/// The allocator state lives in the memfd itself and is protected by a `SharedMutex`, so every
+/// process that opens the arena with `SharedArena::open` can allocate and free. When the arena
+/// runs out of space it grows the memfd; other processes remap it on their next access. The
+/// memfd is sealed against shrinking, so no process can make the others fault by truncating it.
+///
+/// ## Example
+///
+/// ```
+/// use std::alloc::Layout;
+/// let mut arena = memfd::alloc::SharedArena::create("arena", 4096).unwrap();
+///
+/// let offset = arena.alloc(Layout::new::<u64>()).unwrap();
+/// unsafe { *(arena.ptr(offset) as *mut u64) = 42 };
+/// arena.free(offset).unwrap();
+/// ```
+pub struct SharedArena {
+    header: Header,
+    heap: Heap,
+}
+
+/// The fixed-size header of an arena, holding its size, free list head and lock.
+struct Header(MmapMut);
+
+/// The growable mapping of a whole arena.
+///
+/// It is kept apart from the header so the lock in the header can be held while the heap is
+/// remapped.
+struct Heap {
+    memfd: MemFd,
+    map: MmapMut,
+}
+
+impl SharedArena {
+    /// Creates an arena named `name` with an initial size of at least `size` bytes.
+    pub fn create<S: Into<Vec<u8>>>(name: S, size: usize) -> Result<SharedArena, Error> {
+        let page = page_size() as u64;
+        let len = (size as u64).max(2 * HEADER_SIZE).div_ceil(page) * page;
+        let memfd = OpenOptions::new()
+            .close_on_exec(true)
+            .allow_sealing(true)
+            .create(name)?;
+        memfd.set_len(len)?;
+        memfd.add_seals(&shrink_seal())?;
+
+        let header = Header(memfd.map_range_mut(0, HEADER_SIZE as usize)?);
+        unsafe { SharedMutex::init(&header.0, MUTEX_OFFSET)? };
+        let map = memfd.map_mut()?;
+        let arena = SharedArena {
+            header,
+            heap: Heap { memfd, map },
+        };
+        unsafe { ptr::write(arena.header.0.as_ptr() as *mut u64, MAGIC) };
+        arena
+            .heap
+            .free_list(&arena.header)
+            .insert(HEADER_SIZE, len - HEADER_SIZE);
+        arena.header.len().store(len, Ordering::Release);
+        Ok(arena)
+    }
+
+    /// Opens an arena created by `SharedArena::create`, typically in another process.
+    ///
+    /// Fails with `Error::MissingSeals` unless the memfd is sealed with `Seal::Shrink`.
+    pub fn open(memfd: MemFd) -> Result<SharedArena, Error> {
+        let memfd = memfd.require_seals(&shrink_seal())?.into_memfd();
+        let header = memfd
+            .map_range_mut(0, HEADER_SIZE as usize)
+            .map_err(|_| invalid("memfd does not contain an arena"))?;
+        if unsafe { ptr::read(header.as_ptr() as *const u64) } != MAGIC {
+            return Err(invalid("memfd does not contain an arena").into());
+        }
+        let map = memfd.map_mut()?;
+        let mut arena = SharedArena {
+            header: Header(header),
+            heap: Heap { memfd, map },
+        };
+        arena.refresh()?;
+        Ok(arena)
+    }
+
+    /// The memfd backing the arena, to be passed to other processes.
+    pub fn memfd(&self) -> &MemFd {
+        &self.heap.memfd
+    }
+
+    /// The current size of the arena in bytes.
+    pub fn size(&self) -> usize {
+        self.heap.map.len()
+    }
+
+    /// Allocates memory for `layout`, growing the arena if needed.
+    pub fn alloc(&mut self, layout: Layout) -> Result<Offset, Error> {
+        let _guard = self.heap.lock(&self.header)?;
+
+        let (size, align) = free_list::request(layout);
+        loop {
+            if let Some(offset) = self.heap.free_list(&self.header).alloc(size, align)? {
+                return Ok(Offset(offset));
+            }
+            self.heap.grow(&self.header, free_list::growth(size, align))?;
+        }
+    }
+
+    /// Frees memory previously returned by `alloc`.
+    ///
+    /// Fails with `InvalidInput` if `offset` does not denote a live allocation.
+    pub fn free(&mut self, offset: Offset) -> Result<(), Error> {
+        let _guard = self.heap.lock(&self.header)?;
+
+        if !self.heap.free_list(&self.header).free(offset.0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "offset does not denote a live allocation",
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Returns a pointer to the memory at `offset` in this process.
+    ///
+    /// The pointer is invalidated by the next `alloc`, `free` or `refresh`, which may remap the
+    /// arena.
+    pub fn ptr(&self, offset: Offset) -> *mut u8 {
+        assert!(offset.0 < self.heap.map.len() as u64, "offset outside the arena");
+        unsafe { self.heap.map.as_ptr().add(offset.0 as usize) as *mut u8 }
+    }
+
+    /// Returns the offset of `ptr`, if it points into this arena.
+    pub fn offset_of<T>(&self, ptr: *const T) -> Option<Offset> {
+        let base = self.heap.map.as_ptr() as usize;
+        let addr = ptr as usize;
+        if addr >= base + HEADER_SIZE as usize && addr < base + self.heap.map.len() {
+            Some(Offset((addr - base) as u64))
+        } else {
+            None
+        }
+    }
+
+    /// Remaps the arena if another process has grown it.
+    pub fn refresh(&mut self) -> Result<(), Error> {
+        self.heap.refresh(&self.header)
+    }
+}
+
+impl Header {
+    fn mutex(&self) -> &SharedMutex {
+        SharedMutex::at(&self.0, MUTEX_OFFSET).expect("the header holds the mutex")
+    }
+
+    fn len(&self) -> &AtomicU64 {
+        unsafe { &*(self.0.as_ptr().add(LEN_OFFSET) as *const AtomicU64) }
+    }
+
+    fn free_head(&self) -> &AtomicU64 {
+        unsafe { &*(self.0.as_ptr().add(FREE_HEAD_OFFSET) as *const AtomicU64) }
+    }
+}
+
+impl Heap {
+    /// Acquires the arena lock and remaps the heap if it has grown.
+    ///
+    /// If a process died while holding the lock, it may have left the free list half updated.
+    /// The list is checked then, and `InvalidData` is returned if it is corrupted.
+    fn lock<'a>(&mut self, header: &'a Header) -> Result<SharedMutexGuard<'a>, Error> {
+        let (guard, owner_died) = match header.mutex().lock() {
+            Ok(guard) => (guard, false),
+            Err(died) => (died.into_guard(), true),
+        };
+        self.refresh(header)?;
+        if owner_died {
+            self.free_list(header).check()?;
+        }
+        Ok(guard)
+    }
+
+    fn refresh(&mut self, header: &Header) -> Result<(), Error> {
+        let len = header.len().load(Ordering::Acquire);
+        if len != self.map.len() as u64 {
+            self.map = self.memfd.map_mut()?;
+            if self.map.len() as u64 != len {
+                return Err(invalid("arena size does not match its memfd").into());
+            }
+        }
+        Ok(())
+    }
+
+    fn grow(&mut self, header: &Header, min: u64) -> Result<(), Error> {
+        let page = page_size() as u64;
+        let old_len = self.map.len() as u64;
+        let new_len = (old_len * 2).max(old_len + min).div_ceil(page) * page;
+
+        self.memfd.set_len(new_len)?;
+        self.map = self.memfd.map_mut()?;
+        header.len().store(new_len, Ordering::Release);
+        self.free_list(header).insert(old_len, new_len - old_len);
+        Ok(())
+    }
+
+    fn free_list<'a>(&'a self, header: &'a Header) -> FreeList<'a> {
+        unsafe {
+            FreeList::new(
+                self.map.as_ptr() as *mut u8,
+                HEADER_SIZE,
+                self.map.len() as u64,
+                header.free_head(),
+            )
+        }
+    }
+}
+
+fn shrink_seal() -> SealsHashSet {
+    [Seal::Shrink].iter().cloned().collect()
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A pointer stored inside an arena, relative to its own location.
+///
+/// Because only the distance to the target is stored, a `RelPtr` placed in shared memory is
+/// valid in every process, regardless of the address the arena is mapped at. It must only be
+/// dereferenced at its location in the arena, not after being copied elsewhere.
+#[repr(transparent)]
+pub struct RelPtr<T> {
+    delta: i64,
+    marker: PhantomData<*const T>,
+}
+
+impl<T> RelPtr<T> {
+    /// A null relative pointer.
+    pub fn null() -> RelPtr<T> {
+        RelPtr {
+            delta: 0,
+            marker: PhantomData,
+        }
+    }
+
+    /// Whether this pointer is null.
+    pub fn is_null(&self) -> bool {
+        self.delta == 0
+    }
+
+    /// Points this pointer at `target`, or makes it null.
+    pub fn set(&mut self, target: Option<*const T>) {
+        self.delta = match target {
+            Some(target) => target as i64 - self as *const RelPtr<T> as i64,
+            None => 0,
+        };
+    }
+
+    /// Returns the absolute address of the target in this process, or null.
+    pub fn as_ptr(&self) -> *const T {
+        if self.delta == 0 {
+            ptr::null()
+        } else {
+            (self as *const RelPtr<T> as i64 + self.delta) as *const T
+        }
+    }
+
+    /// Returns a reference to the target.
+    ///
+    /// # Safety
+    ///
+    /// The target must be a valid, initialized `T` inside the same mapping.
+    pub unsafe fn as_ref(&self) -> Option<&T> {
+        self.as_ptr().as_ref()
+    }
+}
+
+impl<T> fmt::Debug for RelPtr<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RelPtr")
+            .field("delta", &self.delta)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc;
+    use std::mem;
+    use test_util::pass_memfd;
+
+    #[test]
+    fn alloc_respects_alignment() {
+        let mut arena = SharedArena::create("arena", 4096).unwrap();
+        for &align in &[1, 8, 16, 64, 256, 4096] {
+            let offset = arena
+                .alloc(Layout::from_size_align(24, align).unwrap())
+                .unwrap();
+            assert_eq!(0, arena.ptr(offset) as usize % align);
+        }
+    }
+
+    #[test]
+    fn allocations_do_not_overlap_and_are_reused() {
+        let mut arena = SharedArena::create("arena", 4096).unwrap();
+        let layout = Layout::from_size_align(100, 8).unwrap();
+
+        let offsets: Vec<_> = (0..10).map(|_| arena.alloc(layout).unwrap()).collect();
+        for (i, &offset) in offsets.iter().enumerate() {
+            unsafe { ptr::write_bytes(arena.ptr(offset), i as u8, 100) };
+        }
+        for (i, &offset) in offsets.iter().enumerate() {
+            let bytes = unsafe { ::std::slice::from_raw_parts(arena.ptr(offset), 100) };
+            assert!(bytes.iter().all(|&b| b == i as u8));
+        }
+
+        for &offset in &offsets {
+            arena.free(offset).unwrap();
+        }
+        assert_eq!(offsets[0], arena.alloc(layout).unwrap());
+    }
+
+    #[test]
+    fn free_rejects_invalid_offsets() {
+        let mut arena = SharedArena::create("arena", 4096).unwrap();
+        let offset = arena.alloc(Layout::new::<u64>()).unwrap();
+        assert!(arena.free(Offset(offset.0 + 16)).is_err());
+        arena.free(offset).unwrap();
+        assert!(arena.free(offset).is_err());
+    }
+
+    #[test]
+    fn grows_and_other_side_remaps() {
+        let mut arena = SharedArena::create("arena", 4096).unwrap();
+        let mut other = SharedArena::open(pass_memfd(arena.memfd())).unwrap();
+
+        let big = arena
+            .alloc(Layout::from_size_align(64 * 1024, 8).unwrap())
+            .unwrap();
+        assert!(arena.size() > 64 * 1024);
+        unsafe { *arena.ptr(big).add(64 * 1024 - 1) = 7 };
+
+        other.refresh().unwrap();
+        assert_eq!(arena.size(), other.size());
+        assert_eq!(7, unsafe { *other.ptr(big).add(64 * 1024 - 1) });
+    }
+
+    #[test]
+    fn free_list_is_checked_after_owner_died() {
+        fn die_holding_lock(arena: &SharedArena, free_head: Option<u64>) {
+            let pid = unsafe { libc::fork() };
+            if pid == 0 {
+                mem::forget(arena.header.mutex().lock());
+                if let Some(head) = free_head {
+                    arena.header.free_head().store(head, Ordering::Relaxed);
+                }
+                unsafe { libc::_exit(0) };
+            }
+            let mut status = 0;
+            unsafe { libc::waitpid(pid, &mut status, 0) };
+        }
+
+        let mut arena = SharedArena::create("arena", 4096).unwrap();
+        die_holding_lock(&arena, None);
+        let offset = arena.alloc(Layout::new::<u64>()).unwrap();
+
+        die_holding_lock(&arena, Some(8));
+        match arena.free(offset) {
+            Err(Error::Io(err)) => assert_eq!(io::ErrorKind::InvalidData, err.kind()),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn open_requires_shrink_seal() {
+        let arena = SharedArena::create("arena", 4096).unwrap();
+        assert!(arena.memfd().seals().unwrap().contains(&Seal::Shrink));
+
+        let unsealed = OpenOptions::new().create("unsealed").unwrap();
+        unsealed.set_len(4096).unwrap();
+        match SharedArena::open(unsealed) {
+            Err(Error::MissingSeals(missing)) => assert!(missing.contains(&Seal::Shrink)),
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("opened an unsealed memfd"),
+        }
+    }
+
+    #[test]
+    fn rel_ptr_across_processes() {
+        #[repr(C)]
+        struct Node {
+            value: u64,
+            next: RelPtr<Node>,
+        }
+
+        let mut arena = SharedArena::create("arena", 4096).unwrap();
+        let layout = Layout::new::<Node>();
+        let first = arena.alloc(layout).unwrap();
+        let second = arena.alloc(layout).unwrap();
+
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            let ok = unsafe {
+                let first = &mut *(arena.ptr(first) as *mut Node);
+                let second = &mut *(arena.ptr(second) as *mut Node);
+                second.value = 2;
+                second.next = RelPtr::null();
+                first.value = 1;
+                first.next.set(Some(second as *const Node));
+                true
+            };
+            unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+        }
+        let mut status = 0;
+        unsafe { libc::waitpid(pid, &mut status, 0) };
+
+        // Map the arena at a different address than the child used.
+        let other = SharedArena::open(pass_memfd(arena.memfd())).unwrap();
+        drop(arena);
+        let first = unsafe { &*(other.ptr(first) as *const Node) };
+        let second = unsafe { first.next.as_ref() }.unwrap();
+        assert_eq!(1, first.value);
+        assert_eq!(2, second.value);
+        assert!(second.next.is_null());
+        assert_eq!(8, mem::size_of::<RelPtr<Node>>());
+    }
+}
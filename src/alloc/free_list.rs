@@ -0,0 +1,192 @@
+//! A first-fit, address-ordered free list over a contiguous region of memory.
+//!
+//! Blocks are addressed by their offset from the start of the region, so the same list can be
+//! walked in every process mapping it. Each block starts with a 16 byte header holding its size
+//! and either the offset of the next free block or, once allocated, a tag used to reject bogus
+//! frees. Offset 0 terminates the list, so the region must reserve some header space in front.
+
+use std::alloc::Layout;
+use std::io;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const BLOCK_HEADER: u64 = 16;
+const MIN_BLOCK: u64 = 32;
+const ALLOCATED: u64 = 0xa110_ca7e_db10_c4a5;
+
+/// The block size and alignment needed to satisfy `layout`.
+pub fn request(layout: Layout) -> (u64, u64) {
+    let size = (layout.size().max(1) as u64).div_ceil(16) * 16 + BLOCK_HEADER;
+    let align = (layout.align() as u64).max(16);
+    (size, align)
+}
+
+/// The number of bytes a region has to grow by to fit a block of `size` aligned to `align`.
+pub fn growth(size: u64, align: u64) -> u64 {
+    size + align + MIN_BLOCK
+}
+
+pub struct FreeList<'a> {
+    base: *mut u8,
+    start: u64,
+    len: u64,
+    head: &'a AtomicU64,
+}
+
+impl<'a> FreeList<'a> {
+    /// Manages the bytes from `start` to `len` of the region at `base`.
+    ///
+    /// # Safety
+    ///
+    /// `base` must be valid for `len` bytes, 16 byte aligned, and `head` must only be accessed
+    /// through free lists over the same region, serialized by the caller.
+    pub unsafe fn new(base: *mut u8, start: u64, len: u64, head: &'a AtomicU64) -> FreeList<'a> {
+        FreeList {
+            base,
+            start,
+            len,
+            head,
+        }
+    }
+
+    /// Allocates a block of `size` bytes whose payload is aligned to `align`.
+    ///
+    /// Returns the offset of the payload, or `None` if no free block is large enough.
+    pub fn alloc(&self, size: u64, align: u64) -> io::Result<Option<u64>> {
+        let mut prev = 0;
+        let mut block = self.head.load(Ordering::Relaxed);
+        let mut steps = 0;
+        while block != 0 {
+            steps += 1;
+            let block_size = self.read(block);
+            let next = self.read(block + 8);
+            if block < self.start
+                || block_size < MIN_BLOCK
+                || block + block_size > self.len
+                || steps > self.len
+            {
+                return Err(corrupted());
+            }
+
+            let mut payload = (block + BLOCK_HEADER).div_ceil(align) * align;
+            while payload - BLOCK_HEADER != block && payload - BLOCK_HEADER - block < MIN_BLOCK {
+                payload += align;
+            }
+            let start = payload - BLOCK_HEADER;
+            let end = start + size;
+            let block_end = block + block_size;
+            if end <= block_end {
+                let mut alloc_size = size;
+                let mut successor = next;
+                if block_end - end >= MIN_BLOCK {
+                    self.write(end, block_end - end);
+                    self.write(end + 8, next);
+                    successor = end;
+                } else {
+                    alloc_size = block_end - start;
+                }
+
+                if start == block {
+                    self.link(prev, successor);
+                } else {
+                    self.write(block, start - block);
+                    self.write(block + 8, successor);
+                }
+
+                self.write(start, alloc_size);
+                self.write(start + 8, ALLOCATED);
+                return Ok(Some(payload));
+            }
+
+            prev = block;
+            block = next;
+        }
+        Ok(None)
+    }
+
+    /// Checks that the free blocks lie inside the region, in ascending order and without
+    /// overlapping, e.g. after a process died while modifying the list.
+    ///
+    /// Blocks that were being allocated or freed at that point may be lost, but the list is
+    /// usable if this succeeds.
+    pub fn check(&self) -> io::Result<()> {
+        let mut end = self.start;
+        let mut block = self.head.load(Ordering::Relaxed);
+        while block != 0 {
+            if block < end || !block.is_multiple_of(16) || block > self.len - MIN_BLOCK {
+                return Err(corrupted());
+            }
+            let size = self.read(block);
+            if size < MIN_BLOCK || size > self.len - block {
+                return Err(corrupted());
+            }
+            end = block + size;
+            block = self.read(block + 8);
+        }
+        Ok(())
+    }
+
+    /// Frees the block whose payload is at `offset`.
+    ///
+    /// Returns `false` without touching the list if `offset` is not a live allocation.
+    pub fn free(&self, offset: u64) -> bool {
+        let block = offset.wrapping_sub(BLOCK_HEADER);
+        if !offset.is_multiple_of(16)
+            || block < self.start
+            || offset >= self.len
+            || self.read(block + 8) != ALLOCATED
+        {
+            return false;
+        }
+        let size = self.read(block);
+        self.insert(block, size);
+        true
+    }
+
+    /// Inserts a free block, merging it with its neighbours.
+    pub fn insert(&self, block: u64, size: u64) {
+        let mut prev = 0;
+        let mut next = self.head.load(Ordering::Relaxed);
+        while next != 0 && next < block {
+            prev = next;
+            next = self.read(next + 8);
+        }
+
+        let (mut block, mut size) = (block, size);
+        if next != 0 && block + size == next {
+            size += self.read(next);
+            next = self.read(next + 8);
+        }
+        if prev != 0 && prev + self.read(prev) == block {
+            size += self.read(prev);
+            block = prev;
+            self.write(block, size);
+            self.write(block + 8, next);
+            return;
+        }
+
+        self.write(block, size);
+        self.write(block + 8, next);
+        self.link(prev, block);
+    }
+
+    fn link(&self, prev: u64, next: u64) {
+        if prev == 0 {
+            self.head.store(next, Ordering::Relaxed);
+        } else {
+            self.write(prev + 8, next);
+        }
+    }
+
+    fn read(&self, offset: u64) -> u64 {
+        unsafe { ptr::read(self.base.add(offset as usize) as *const u64) }
+    }
+
+    fn write(&self, offset: u64, value: u64) {
+        unsafe { ptr::write(self.base.add(offset as usize) as *mut u64, value) }
+    }
+}
+
+fn corrupted() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "free list is corrupted")
+}
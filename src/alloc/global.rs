@@ -0,0 +1,351 @@
+use libc;
+use std::alloc::{GlobalAlloc, Layout};
+use std::ffi::CStr;
+use std::fs::File;
+use std::marker::PhantomData;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+#[cfg(feature = "nightly")]
+use std::alloc::{AllocError, Allocator};
+
+use super::free_list::{self, FreeList};
+use mmap::page_size;
+use {OpenOptions, MAX_NAME_LEN};
+
+/// The default size of each memfd the allocator creates.
+pub const DEFAULT_CHUNK_SIZE: usize = 1 << 20;
+
+const CHUNK_HEADER: u64 = 64;
+
+/// The header at the start of every chunk, in the chunk's own mapping.
+#[repr(C)]
+struct Chunk {
+    next: *mut Chunk,
+    fd: RawFd,
+    len: u64,
+    free_head: AtomicU64,
+}
+
+/// An allocator carving memory out of memfds.
+///
+/// Memory is taken from chunks of at least `DEFAULT_CHUNK_SIZE` bytes, each backed by its own
+/// memfd created with the allocator's `OpenOptions` and mapped shared. The memfds stay open for
+/// the lifetime of the process, so the heap can be inspected or handed to another process
+/// through `memfds`.
+///
+/// Creating chunks does not allocate from the heap, which makes the allocator usable as the
+/// global allocator. With the `nightly` feature it also implements `Allocator`, so individual
+/// collections can be placed in memfds.
+///
+/// ## Example
+///
+/// ```
+/// use memfd::alloc::MemfdAllocator;
+///
+/// #[global_allocator]
+/// static HEAP: MemfdAllocator = MemfdAllocator::new("heap");
+///
+/// fn main() {
+///     let v = vec![1u8, 2, 3];
+///     assert_eq!(6u8, v.iter().sum());
+///     assert!(HEAP.memfds().count() > 0);
+/// }
+/// ```
+pub struct MemfdAllocator {
+    name: &'static str,
+    options: OpenOptions,
+    chunk_size: usize,
+    lock: Mutex<()>,
+    chunks: AtomicPtr<Chunk>,
+}
+
+impl MemfdAllocator {
+    /// Creates an allocator whose memfds are named `name` and closed on exec.
+    ///
+    /// Names longer than 249 bytes are truncated, and at the first NUL byte.
+    pub const fn new(name: &'static str) -> MemfdAllocator {
+        let mut options = OpenOptions::new();
+        options.flags = libc::MFD_CLOEXEC;
+        MemfdAllocator::with_options(name, options)
+    }
+
+    /// Creates an allocator whose memfds are created with `options`.
+    ///
+    /// Chunks of hugetlb-backed memfds are rounded up to the huge page size.
+    pub const fn with_options(name: &'static str, options: OpenOptions) -> MemfdAllocator {
+        MemfdAllocator {
+            name,
+            options,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            lock: Mutex::new(()),
+            chunks: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Sets the minimum size of each memfd the allocator creates.
+    pub const fn chunk_size(mut self, chunk_size: usize) -> MemfdAllocator {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// The memfds backing the allocator, most recently created first.
+    ///
+    /// Each memfd starts with a small header, followed by the allocated and free blocks.
+    pub fn memfds(&self) -> Memfds<'_> {
+        Memfds {
+            chunk: self.chunks.load(Ordering::Acquire),
+            marker: PhantomData,
+        }
+    }
+
+    fn alloc_block(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let _guard = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let (size, align) = free_list::request(layout);
+
+        let mut chunk = self.chunks.load(Ordering::Acquire);
+        while !chunk.is_null() {
+            if let Some(ptr) = unsafe { alloc_in(chunk, size, align) } {
+                return Some(ptr);
+            }
+            chunk = unsafe { (*chunk).next };
+        }
+
+        let chunk = self.new_chunk(CHUNK_HEADER + free_list::growth(size, align))?;
+        unsafe { alloc_in(chunk, size, align) }
+    }
+
+    fn free_block(&self, ptr: NonNull<u8>) {
+        let _guard = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let addr = ptr.as_ptr() as u64;
+
+        let mut chunk = self.chunks.load(Ordering::Acquire);
+        while !chunk.is_null() {
+            let base = chunk as u64;
+            unsafe {
+                if addr > base && addr < base + (*chunk).len {
+                    let freed = free_list(chunk).free(addr - base);
+                    debug_assert!(freed, "freed a pointer that is not a live allocation");
+                    return;
+                }
+                chunk = (*chunk).next;
+            }
+        }
+        debug_assert!(false, "freed a pointer not owned by this allocator");
+    }
+
+    /// Creates, maps and publishes a chunk of at least `min` bytes. Must hold the lock.
+    fn new_chunk(&self, min: u64) -> Option<*mut Chunk> {
+        let page = match self.options.hugetlb {
+            Some(size) => size.page_size(),
+            None => page_size() as u64,
+        };
+        let len = min.max(self.chunk_size as u64).div_ceil(page) * page;
+
+        let mut name = [0u8; MAX_NAME_LEN + 1];
+        let bytes = self.name.as_bytes();
+        let n = bytes
+            .iter()
+            .take(MAX_NAME_LEN)
+            .position(|&b| b == 0)
+            .unwrap_or(bytes.len().min(MAX_NAME_LEN));
+        name[..n].copy_from_slice(&bytes[..n]);
+        let name = CStr::from_bytes_until_nul(&name).ok()?;
+
+        let file: File = self.options.create_raw(name).ok()?;
+        file.set_len(len).ok()?;
+        let base = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return None;
+        }
+
+        let chunk = base as *mut Chunk;
+        unsafe {
+            ptr::write(
+                chunk,
+                Chunk {
+                    next: self.chunks.load(Ordering::Relaxed),
+                    fd: file.into_raw_fd(),
+                    len,
+                    free_head: AtomicU64::new(0),
+                },
+            );
+            free_list(chunk).insert(CHUNK_HEADER, len - CHUNK_HEADER);
+        }
+        self.chunks.store(chunk, Ordering::Release);
+        Some(chunk)
+    }
+}
+
+unsafe fn free_list<'a>(chunk: *mut Chunk) -> FreeList<'a> {
+    FreeList::new(
+        chunk as *mut u8,
+        CHUNK_HEADER,
+        (*chunk).len,
+        &(*chunk).free_head,
+    )
+}
+
+unsafe fn alloc_in(chunk: *mut Chunk, size: u64, align: u64) -> Option<NonNull<u8>> {
+    let offset = free_list(chunk).alloc(size, align).ok()??;
+    NonNull::new((chunk as *mut u8).add(offset as usize))
+}
+
+impl Drop for MemfdAllocator {
+    fn drop(&mut self) {
+        let mut chunk = *self.chunks.get_mut();
+        while !chunk.is_null() {
+            unsafe {
+                let Chunk { next, fd, len, .. } = ptr::read(chunk);
+                libc::munmap(chunk as *mut libc::c_void, len as usize);
+                libc::close(fd);
+                chunk = next;
+            }
+        }
+    }
+}
+
+unsafe impl Send for MemfdAllocator {}
+unsafe impl Sync for MemfdAllocator {}
+
+unsafe impl GlobalAlloc for MemfdAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_block(layout)
+            .map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        if let Some(ptr) = NonNull::new(ptr) {
+            self.free_block(ptr);
+        }
+    }
+}
+
+#[cfg(feature = "nightly")]
+unsafe impl Allocator for MemfdAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() == 0 {
+            let dangling = unsafe { NonNull::new_unchecked(layout.align() as *mut u8) };
+            return Ok(NonNull::slice_from_raw_parts(dangling, 0));
+        }
+        self.alloc_block(layout)
+            .map(|ptr| NonNull::slice_from_raw_parts(ptr, layout.size()))
+            .ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            self.free_block(ptr);
+        }
+    }
+}
+
+/// An iterator over the memfds backing a `MemfdAllocator`, see `MemfdAllocator::memfds`.
+pub struct Memfds<'a> {
+    chunk: *mut Chunk,
+    marker: PhantomData<&'a MemfdAllocator>,
+}
+
+impl<'a> Iterator for Memfds<'a> {
+    type Item = RawFd;
+
+    fn next(&mut self) -> Option<RawFd> {
+        if self.chunk.is_null() {
+            return None;
+        }
+        // Chunks are never unmapped, and their `next` and `fd` never change once published.
+        let chunk = unsafe { &*self.chunk };
+        self.chunk = chunk.next;
+        Some(chunk.fd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::ManuallyDrop;
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::FromRawFd;
+
+    fn read_back(fd: RawFd, offset: u64, len: usize) -> Vec<u8> {
+        let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+        let mut buf = vec![0; len];
+        file.read_exact_at(&mut buf, offset).unwrap();
+        buf
+    }
+
+    #[test]
+    fn allocations_live_in_the_memfd() {
+        let heap = MemfdAllocator::new("heap");
+        let layout = Layout::from_size_align(5, 1).unwrap();
+        let ptr = unsafe { heap.alloc(layout) };
+        assert!(!ptr.is_null());
+        unsafe { ptr::copy_nonoverlapping(b"hello".as_ptr(), ptr, 5) };
+
+        let fds: Vec<_> = heap.memfds().collect();
+        assert_eq!(1, fds.len());
+        let base = heap.chunks.load(Ordering::Acquire) as u64;
+        assert_eq!(b"hello".to_vec(), read_back(fds[0], ptr as u64 - base, 5));
+        unsafe { heap.dealloc(ptr, layout) };
+    }
+
+    #[test]
+    fn reuses_freed_memory_and_adds_chunks() {
+        let heap = MemfdAllocator::new("heap").chunk_size(4096);
+        let layout = Layout::from_size_align(1024, 64).unwrap();
+
+        let first = unsafe { heap.alloc(layout) };
+        assert_eq!(0, first as usize % 64);
+        unsafe { heap.dealloc(first, layout) };
+        assert_eq!(first, unsafe { heap.alloc(layout) });
+
+        let big = Layout::from_size_align(3 * DEFAULT_CHUNK_SIZE, 8).unwrap();
+        let ptr = unsafe { heap.alloc(big) };
+        assert!(!ptr.is_null());
+        unsafe { ptr::write_bytes(ptr, 0xff, big.size()) };
+        assert_eq!(2, heap.memfds().count());
+    }
+
+    #[test]
+    fn concurrent_allocations() {
+        let heap = MemfdAllocator::new("heap");
+        ::std::thread::scope(|s| {
+            for t in 0..4u8 {
+                let heap = &heap;
+                s.spawn(move || {
+                    let layout = Layout::from_size_align(48, 16).unwrap();
+                    for _ in 0..1000 {
+                        unsafe {
+                            let ptr = heap.alloc(layout);
+                            ptr::write_bytes(ptr, t, 48);
+                            assert!((0..48).all(|i| *ptr.add(i) == t));
+                            heap.dealloc(ptr, layout);
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn collections_in_a_memfd() {
+        let heap = MemfdAllocator::new("vec");
+        let mut v = Vec::new_in(&heap);
+        v.extend(0..1000u32);
+        assert_eq!(499500, v.iter().sum::<u32>());
+        let empty: Vec<u64, _> = Vec::with_capacity_in(0, &heap);
+        assert!(empty.is_empty());
+    }
+}
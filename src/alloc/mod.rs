@@ -0,0 +1,13 @@
+//! Allocators carving memory out of memfds.
+//!
+//! `SharedArena` hands out offsets into one growable memfd that several processes can map at
+//! different addresses. `RelPtr` links objects inside such an arena independently of where it
+//! is mapped. `MemfdAllocator` puts a process-local heap into memfds, as the global allocator or,
+//! with the `nightly` feature, for individual collections.
+
+mod arena;
+mod free_list;
+mod global;
+
+pub use self::arena::{Offset, RelPtr, SharedArena};
+pub use self::global::{MemfdAllocator, Memfds, DEFAULT_CHUNK_SIZE};
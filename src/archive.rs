@@ -0,0 +1,171 @@
+use rkyv::api::high::{HighSerializer, HighValidator};
+use rkyv::bytecheck::CheckBytes;
+use rkyv::rancor;
+use rkyv::ser::allocator::ArenaHandle;
+use rkyv::ser::writer::IoWriter;
+use rkyv::Archive;
+use std::fmt;
+use std::io::{self, BufWriter};
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use error::Error;
+use memfd::MemFd;
+use mmap::SealedMmap;
+use sealed::{SealedBuffer, SealedBufferBuilder, VerifiedMemFd};
+use sealing::{Seal, SealsHashSet};
+
+type ArchiveWriter = IoWriter<BufWriter<SealedBufferBuilder>>;
+
+/// Archives `value` with rkyv into a sealed, immutable buffer named `name`.
+///
+/// The archive is streamed straight into the memfd and laid out exactly as rkyv expects it in
+/// memory, so consumers can access it in place with `ArchivedMemFd`.
+///
+/// ## Example
+///
+/// ```
+/// let buf = memfd::to_archive(&vec![1u32, 2, 3], "table").unwrap();
+/// let archived = memfd::ArchivedMemFd::<Vec<u32>>::new(buf.into_memfd()).unwrap();
+/// assert_eq!(&[1, 2, 3], &archived[..]);
+/// ```
+pub fn to_archive<T, S>(value: &T, name: S) -> Result<SealedBuffer, Error>
+where
+    T: for<'a> rkyv::Serialize<HighSerializer<ArchiveWriter, ArenaHandle<'a>, rancor::Error>>,
+    S: Into<Vec<u8>>,
+{
+    let writer = IoWriter::new(BufWriter::new(SealedBufferBuilder::new(name)?));
+    let writer = rkyv::api::high::to_bytes_in::<_, rancor::Error>(value, writer)
+        .map_err(io::Error::other)?;
+    let builder = writer
+        .into_inner()
+        .into_inner()
+        .map_err(|err| err.into_error())?;
+    builder.finish()
+}
+
+/// A read-only view of an rkyv archive inside a sealed memfd.
+///
+/// The memfd must carry `Seal::Shrink` and `Seal::Write`, which guarantee that the archive can
+/// neither be truncated nor modified after it has been validated. The archive is then accessed
+/// directly in the mapping, without copying or deserializing it.
+pub struct ArchivedMemFd<T: Archive> {
+    memfd: VerifiedMemFd,
+    map: SealedMmap,
+    marker: PhantomData<T>,
+}
+
+impl<T> ArchivedMemFd<T>
+where
+    T: Archive,
+    T::Archived: for<'a> CheckBytes<HighValidator<'a, rancor::Error>>,
+{
+    /// Maps and validates the archive in `memfd`, typically received from another process.
+    ///
+    /// Fails with `Error::MissingSeals` if the memfd is not sealed against shrinking and writing,
+    /// and with `InvalidData` if it does not hold a valid archive of `T`.
+    pub fn new(memfd: MemFd) -> Result<ArchivedMemFd<T>, Error> {
+        let required: SealsHashSet = [Seal::Shrink, Seal::Write].iter().cloned().collect();
+        let memfd = memfd.require_seals(&required)?;
+        let map = memfd.map()?;
+        rkyv::access::<T::Archived, rancor::Error>(&map)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(ArchivedMemFd {
+            memfd,
+            map,
+            marker: PhantomData,
+        })
+    }
+}
+
+impl<T: Archive> ArchivedMemFd<T> {
+    /// Returns the underlying memfd.
+    pub fn as_memfd(&self) -> &MemFd {
+        self.memfd.as_memfd()
+    }
+
+    /// Unmaps the archive and returns the underlying memfd.
+    pub fn into_memfd(self) -> MemFd {
+        self.memfd.into_memfd()
+    }
+}
+
+impl<T: Archive> Deref for ArchivedMemFd<T> {
+    type Target = T::Archived;
+
+    fn deref(&self) -> &T::Archived {
+        // Validated in `new`, and the seals rule out any later modification.
+        unsafe { rkyv::access_unchecked::<T::Archived>(&self.map) }
+    }
+}
+
+impl<T: Archive> fmt::Debug for ArchivedMemFd<T>
+where
+    T::Archived: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ArchivedMemFd").field(&**self).finish()
+    }
+}
+
+impl<T: Archive> AsRawFd for ArchivedMemFd<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.memfd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rkyv::{Archive, Serialize};
+
+    #[derive(Archive, Serialize)]
+    struct Model {
+        name: String,
+        weights: Vec<f32>,
+        labels: Vec<(u32, String)>,
+    }
+
+    fn model() -> Model {
+        Model {
+            name: "tiny".to_string(),
+            weights: (0..1000).map(|i| i as f32 * 0.5).collect(),
+            labels: vec![(1, "cat".to_string()), (2, "dog".to_string())],
+        }
+    }
+
+    #[test]
+    fn accesses_archive_in_place() {
+        let buf = to_archive(&model(), "model").unwrap();
+        let archived = ArchivedMemFd::<Model>::new(buf.into_memfd()).unwrap();
+
+        assert_eq!("tiny", archived.name.as_str());
+        assert_eq!(1000, archived.weights.len());
+        assert_eq!(499.5, archived.weights[999].to_native());
+        assert_eq!("dog", archived.labels[1].1.as_str());
+
+        let start = archived.map.as_ptr() as usize;
+        let field = archived.weights.as_ptr() as usize;
+        assert!(field >= start && field < start + archived.map.len());
+    }
+
+    #[test]
+    fn requires_seals() {
+        let mut fd = ::create("unsealed").unwrap();
+        io::Write::write_all(&mut fd, &[0; 64]).unwrap();
+        match ArchivedMemFd::<Model>::new(fd) {
+            Err(Error::MissingSeals(missing)) => assert!(missing.contains(&Seal::Write)),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_archives() {
+        let buf = SealedBuffer::from_bytes("garbage", &[0xff; 64]).unwrap();
+        assert!(ArchivedMemFd::<Model>::new(buf.into_memfd()).is_err());
+
+        let empty = SealedBuffer::from_bytes("empty", &[]).unwrap();
+        assert!(ArchivedMemFd::<Model>::new(empty.into_memfd()).is_err());
+    }
+}
@@ -0,0 +1,551 @@
+//! Asynchronous memfd I/O and descriptor passing for tokio.
+//!
+//! `AsyncMemFd` moves reads, writes and seeks to tokio's blocking thread pool, like
+//! `tokio::fs::File`. `send_memfd` and `recv_memfd` speak the same protocol as their blocking
+//! counterparts in the crate root, so either side of a connection may be asynchronous.
+//!
+//! ## Example
+//!
+//! ```
+//! extern crate memfd;
+//! extern crate tokio;
+//!
+//! use memfd::async_io::{recv_memfd, send_memfd};
+//! use tokio::net::UnixStream;
+//!
+//! fn main() {
+//!     let rt = tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap();
+//!     let _guard = rt.enter();
+//!     let (a, b) = UnixStream::pair().unwrap();
+//!
+//!     let memfd = memfd::create("shared").unwrap();
+//!     rt.block_on(send_memfd(&a, &memfd, b"hello")).unwrap();
+//!     let (_memfd, payload) = rt.block_on(recv_memfd(&b, 64)).unwrap();
+//!     assert_eq!(b"hello", &payload[..]);
+//! }
+//! ```
+
+use std::ffi::{CStr, CString};
+use std::future::{poll_fn, Future};
+use std::io::{self, SeekFrom};
+use std::fs;
+use std::mem::{self, ManuallyDrop};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::pin::Pin;
+use std::ptr;
+use std::task::{Context, Poll};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, Interest, ReadBuf};
+use tokio::net::UnixStream;
+
+use backend::Backend;
+use memfd::{self, MemFd};
+use socket;
+use OpenOptions;
+
+/// A memfd with asynchronous `AsyncRead`, `AsyncWrite` and `AsyncSeek` implementations.
+///
+/// Like `tokio::fs::File`, operations run on tokio's blocking thread pool so that large reads
+/// and writes do not stall the runtime. Must be used within a tokio runtime.
+///
+/// A memfd created with `OpenOptions::zeroize_on_drop` is wiped when the `AsyncMemFd` is
+/// dropped. Flush it first, or a write still in flight may land after the wipe.
+#[derive(Debug)]
+pub struct AsyncMemFd {
+    file: File,
+    name: Option<CString>,
+    options: Option<OpenOptions>,
+    backend: Backend,
+}
+
+impl AsyncMemFd {
+    /// Wraps `memfd` for asynchronous I/O.
+    pub fn new(memfd: MemFd) -> AsyncMemFd {
+        let (file, name, options, backend) = memfd.into_parts();
+        AsyncMemFd {
+            file: File::from_std(file),
+            name,
+            options,
+            backend,
+        }
+    }
+
+    /// The name this memfd was created with, see `MemFd::name`.
+    pub fn name(&self) -> Option<&CStr> {
+        self.name.as_deref()
+    }
+
+    /// The options this memfd was created with, see `MemFd::options`.
+    pub fn options(&self) -> Option<&OpenOptions> {
+        self.options.as_ref()
+    }
+
+    /// How the underlying file was created, see `MemFd::backend`.
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Truncates or extends the memfd to `size` bytes.
+    pub fn set_len(&self, size: u64) -> impl Future<Output = io::Result<()>> + '_ {
+        self.file.set_len(size)
+    }
+
+    /// Converts back into a blocking `MemFd`, once all operations in flight have completed.
+    pub fn into_memfd(self) -> impl Future<Output = MemFd> {
+        let this = ManuallyDrop::new(self);
+        // `this` is never dropped, so each field is moved out exactly once.
+        let (file, mut name, mut options, backend) = unsafe {
+            (
+                ptr::read(&this.file),
+                ptr::read(&this.name),
+                ptr::read(&this.options),
+                this.backend,
+            )
+        };
+        let mut into_std = Box::pin(file.into_std());
+        poll_fn(move |cx| match into_std.as_mut().poll(cx) {
+            Poll::Ready(file) => Poll::Ready(MemFd::from_parts(
+                file,
+                name.take(),
+                options.take(),
+                backend,
+            )),
+            Poll::Pending => Poll::Pending,
+        })
+    }
+}
+
+impl Drop for AsyncMemFd {
+    fn drop(&mut self) {
+        if self.options.is_some_and(|options| options.is_zeroize_on_drop()) {
+            // tokio's `File` cannot be borrowed as a std one, so wipe through its descriptor.
+            let file = ManuallyDrop::new(unsafe { fs::File::from_raw_fd(self.file.as_raw_fd()) });
+            let _ = memfd::zeroize(&file);
+        }
+    }
+}
+
+impl From<MemFd> for AsyncMemFd {
+    fn from(memfd: MemFd) -> AsyncMemFd {
+        AsyncMemFd::new(memfd)
+    }
+}
+
+impl AsRawFd for AsyncMemFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl AsyncRead for AsyncMemFd {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for AsyncMemFd {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.file).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_shutdown(cx)
+    }
+}
+
+impl AsyncSeek for AsyncMemFd {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        Pin::new(&mut self.file).start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<u64>> {
+        Pin::new(&mut self.file).poll_complete(cx)
+    }
+}
+
+/// Sends `memfd` together with `payload` over `stream`, see `memfd::send_memfd`.
+///
+/// The future borrows `memfd` until it completes, so the memfd cannot be closed while the
+/// message is still to be sent:
+///
+/// ```compile_fail,E0505
+/// # extern crate memfd;
+/// # extern crate tokio;
+/// # fn main() {
+/// let (a, _b) = tokio::net::UnixStream::pair().unwrap();
+/// let memfd = memfd::create("shared").unwrap();
+/// let send = memfd::async_io::send_memfd(&a, &memfd, b"hello");
+/// drop(memfd);
+/// # drop(send);
+/// # }
+/// ```
+pub fn send_memfd<'a>(
+    stream: &'a UnixStream,
+    memfd: &'a MemFd,
+    payload: &'a [u8],
+) -> impl Future<Output = io::Result<()>> + 'a {
+    send_memfds(stream, &[memfd], payload)
+}
+
+/// Sends up to `MAX_FDS` memfds together with `payload` over `stream`, see
+/// `memfd::send_memfds`.
+pub fn send_memfds<'a>(
+    stream: &'a UnixStream,
+    memfds: &[&'a MemFd],
+    payload: &'a [u8],
+) -> impl Future<Output = io::Result<()>> + 'a {
+    let mut send = socket::message_header(memfds, payload)
+        .map(|header| SendMessage {
+            stream,
+            header,
+            payload,
+            memfds: memfds.to_vec(),
+            sent: None,
+        })
+        .map_err(Some);
+    poll_fn(move |cx| match send {
+        Ok(ref mut send) => send.poll(cx),
+        Err(ref mut err) => Poll::Ready(Err(err.take().expect("future polled after completion"))),
+    })
+}
+
+/// Receives a single memfd and its payload, see `memfd::recv_memfd`.
+pub fn recv_memfd(
+    stream: &UnixStream,
+    max_payload: usize,
+) -> impl Future<Output = io::Result<(MemFd, Vec<u8>)>> + '_ {
+    let mut recv = Recv::new(stream, max_payload);
+    poll_fn(move |cx| match recv.poll(cx) {
+        Poll::Ready(Ok((memfds, payload))) => {
+            Poll::Ready(socket::first_memfd(memfds).map(|memfd| (memfd, payload)))
+        }
+        Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+        Poll::Pending => Poll::Pending,
+    })
+}
+
+/// Receives the memfds and payload of one message, see `memfd::recv_memfds`.
+pub fn recv_memfds(
+    stream: &UnixStream,
+    max_payload: usize,
+) -> impl Future<Output = io::Result<(Vec<MemFd>, Vec<u8>)>> + '_ {
+    let mut recv = Recv::new(stream, max_payload);
+    poll_fn(move |cx| recv.poll(cx))
+}
+
+struct SendMessage<'a> {
+    stream: &'a UnixStream,
+    header: [u8; 4],
+    payload: &'a [u8],
+    memfds: Vec<&'a MemFd>,
+    /// The number of bytes sent, once the descriptors have gone out with the first ones.
+    sent: Option<usize>,
+}
+
+impl<'a> SendMessage<'a> {
+    fn poll(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        let header = self.header;
+        let total = header.len() + self.payload.len();
+
+        loop {
+            if self.sent == Some(total) {
+                return Poll::Ready(Ok(()));
+            }
+            match self.stream.poll_write_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+
+            let (stream, payload) = (self.stream, self.payload);
+            let res = match self.sent {
+                None => {
+                    let fds: Vec<RawFd> = self.memfds.iter().map(|memfd| memfd.as_raw_fd()).collect();
+                    stream.try_io(Interest::WRITABLE, || {
+                        socket::send_with_fds(stream.as_raw_fd(), &header, payload, &fds)
+                    })
+                }
+                Some(sent) if sent < header.len() => stream.try_write(&header[sent..]),
+                Some(sent) => stream.try_write(&payload[sent - header.len()..]),
+            };
+            match res {
+                Ok(n) => self.sent = Some(self.sent.unwrap_or(0) + n),
+                // Readiness was cleared; poll it again to register for the next wake-up.
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+    }
+}
+
+struct Recv<'a> {
+    stream: &'a UnixStream,
+    max_payload: usize,
+    header: [u8; 4],
+    /// The number of header bytes read, once the descriptors have been received.
+    read: Option<usize>,
+    memfds: Vec<MemFd>,
+    /// Why the message is rejected, reported once its payload has been discarded.
+    error: Option<io::Error>,
+    body: Option<Body>,
+}
+
+enum Body {
+    /// The payload buffer and the number of bytes filled in.
+    Payload(Vec<u8>, usize),
+    /// The number of payload bytes still to be discarded.
+    Discard(usize),
+}
+
+impl<'a> Recv<'a> {
+    fn new(stream: &'a UnixStream, max_payload: usize) -> Recv<'a> {
+        Recv {
+            stream,
+            max_payload,
+            header: [0; 4],
+            read: None,
+            memfds: Vec::new(),
+            error: None,
+            body: None,
+        }
+    }
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<io::Result<(Vec<MemFd>, Vec<u8>)>> {
+        loop {
+            match self.body {
+                Some(Body::Payload(ref payload, filled)) if filled == payload.len() => {
+                    let payload = match self.body.take() {
+                        Some(Body::Payload(payload, _)) => payload,
+                        _ => unreachable!(),
+                    };
+                    return Poll::Ready(Ok((mem::take(&mut self.memfds), payload)));
+                }
+                Some(Body::Discard(0)) => {
+                    self.body = None;
+                    return Poll::Ready(Err(self.error.take().unwrap()));
+                }
+                _ => {}
+            }
+            match self.stream.poll_read_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+
+            let stream = self.stream;
+            let mut scratch = [0; 4096];
+            let res = match (self.read, &mut self.body) {
+                (None, _) => {
+                    let header = &mut self.header;
+                    match stream.try_io(Interest::READABLE, || {
+                        socket::recv_with_fds(stream.as_raw_fd(), header)
+                    }) {
+                        Ok((read, fds)) => {
+                            // Keep reading a rejected message so the stream stays in sync.
+                            match socket::into_memfds(fds) {
+                                Ok(memfds) => self.memfds = memfds,
+                                Err(err) => self.error = Some(err),
+                            }
+                            Ok(read)
+                        }
+                        Err(err) => Err(err),
+                    }
+                }
+                (Some(read), &mut None) => stream.try_read(&mut self.header[read..]),
+                (Some(_), &mut Some(Body::Payload(ref mut payload, filled))) => {
+                    stream.try_read(&mut payload[filled..])
+                }
+                (Some(_), &mut Some(Body::Discard(remaining))) => {
+                    let len = remaining.min(scratch.len());
+                    stream.try_read(&mut scratch[..len])
+                }
+            };
+
+            match res {
+                Ok(0) if self.read.is_some() => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed in the middle of a message",
+                    )));
+                }
+                Ok(n) => self.advance(n),
+                // Readiness was cleared; poll it again to register for the next wake-up.
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+    }
+
+    fn advance(&mut self, n: usize) {
+        match (self.read, &mut self.body) {
+            (_, &mut Some(Body::Payload(_, ref mut filled))) => *filled += n,
+            (_, &mut Some(Body::Discard(ref mut remaining))) => *remaining -= n,
+            (read, body) => {
+                let read = read.map_or(n, |read| read + n);
+                self.read = Some(read);
+                if read == self.header.len() {
+                    let len = u32::from_ne_bytes(self.header) as usize;
+                    if self.error.is_none() {
+                        if let Err(err) = socket::check_payload_len(len, self.max_payload) {
+                            self.memfds.clear();
+                            self.error = Some(err);
+                        }
+                    }
+                    *body = Some(if self.error.is_some() {
+                        Body::Discard(len)
+                    } else {
+                        Body::Payload(vec![0; len], 0)
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use create;
+    use std::io::Write;
+    use std::os::unix::net;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+    use tokio::runtime::{Builder, Runtime};
+
+    fn runtime() -> Runtime {
+        Builder::new_current_thread().enable_io().build().unwrap()
+    }
+
+    #[test]
+    fn async_read_write_seek() {
+        let rt = runtime();
+        let mut memfd = AsyncMemFd::new(create("async").unwrap());
+        let data = vec![7u8; 1 << 20];
+
+        rt.block_on(memfd.write_all(&data)).unwrap();
+        rt.block_on(memfd.flush()).unwrap();
+        assert_eq!(1, rt.block_on(memfd.seek(SeekFrom::Start(1))).unwrap());
+        let mut read = Vec::new();
+        rt.block_on(memfd.read_to_end(&mut read)).unwrap();
+        assert_eq!(data.len() - 1, read.len());
+
+        let memfd = rt.block_on(memfd.into_memfd());
+        assert_eq!(
+            Some(&b"async"[..]),
+            memfd.name().map(|name| name.to_bytes())
+        );
+        assert_eq!(1 << 20, memfd.as_file().metadata().unwrap().len());
+    }
+
+    #[test]
+    fn zeroizes_on_drop() {
+        use std::os::unix::fs::FileExt;
+
+        let rt = runtime();
+        let _guard = rt.enter();
+        let mut memfd = ::OpenOptions::new().zeroize_on_drop(true).create("token").unwrap();
+        memfd.write_all(b"secret").unwrap();
+        let copy = memfd.as_file().try_clone().unwrap();
+
+        let memfd = rt.block_on(AsyncMemFd::new(memfd).into_memfd());
+        let memfd = AsyncMemFd::new(memfd);
+        let mut data = [0xff; 6];
+        copy.read_exact_at(&mut data, 0).unwrap();
+        assert_eq!(b"secret", &data);
+
+        drop(memfd);
+        copy.read_exact_at(&mut data, 0).unwrap();
+        assert_eq!([0; 6], data);
+    }
+
+    #[test]
+    fn async_send_to_blocking_recv() {
+        let rt = runtime();
+        let _guard = rt.enter();
+        let (a, b) = net::UnixStream::pair().unwrap();
+        a.set_nonblocking(true).unwrap();
+        let a = UnixStream::from_std(a).unwrap();
+
+        let first = create("first").unwrap();
+        let second = create("second").unwrap();
+        second.set_len(42).unwrap();
+        rt.block_on(send_memfds(&a, &[&first, &second], b"payload"))
+            .unwrap();
+
+        let (memfds, payload) = ::recv_memfds(&b, 64).unwrap();
+        assert_eq!(b"payload", &payload[..]);
+        assert_eq!(42, memfds[1].as_file().metadata().unwrap().len());
+    }
+
+    #[test]
+    fn async_send_rejects_too_many_memfds() {
+        let rt = runtime();
+        let _guard = rt.enter();
+        let (a, _b) = UnixStream::pair().unwrap();
+
+        let memfd = create("many").unwrap();
+        let memfds = vec![&memfd; ::MAX_FDS + 1];
+        let err = rt.block_on(send_memfds(&a, &memfds, b"")).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn blocking_send_to_async_recv() {
+        let rt = runtime();
+        let _guard = rt.enter();
+        let (a, b) = net::UnixStream::pair().unwrap();
+        b.set_nonblocking(true).unwrap();
+        let b = UnixStream::from_std(b).unwrap();
+
+        let mut memfd = create("passed").unwrap();
+        memfd.write_all(b"shared").unwrap();
+        let payload = vec![3u8; 1 << 20];
+        let sender = ::std::thread::spawn(move || {
+            ::send_memfd(&a, &memfd, &payload).unwrap();
+            a
+        });
+
+        let (received, payload) = rt.block_on(recv_memfd(&b, 1 << 20)).unwrap();
+        assert_eq!(1 << 20, payload.len());
+        let mut data = [0; 6];
+        received.map().unwrap().read_at(0, &mut data).unwrap();
+        assert_eq!(b"shared", &data);
+
+        drop(sender.join().unwrap());
+        let err = rt.block_on(recv_memfd(&b, 64)).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[test]
+    fn async_recv_discards_oversized_payload() {
+        let rt = runtime();
+        let _guard = rt.enter();
+        let (a, b) = net::UnixStream::pair().unwrap();
+        b.set_nonblocking(true).unwrap();
+        let b = UnixStream::from_std(b).unwrap();
+
+        let memfd = create("passed").unwrap();
+        let sender = ::std::thread::spawn(move || {
+            ::send_memfd(&a, &memfd, &vec![3u8; 1 << 20]).unwrap();
+            ::send_memfd(&a, &memfd, b"next").unwrap();
+            a
+        });
+
+        let err = rt.block_on(recv_memfd(&b, 64)).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        let (_, payload) = rt.block_on(recv_memfd(&b, 64)).unwrap();
+        assert_eq!(b"next", &payload[..]);
+        drop(sender.join().unwrap());
+    }
+}
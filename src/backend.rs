@@ -0,0 +1,184 @@
+use libc;
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use OpenOptions;
+
+/// The tmpfs directory fallback files are created in.
+const SHM_DIR: &str = "/dev/shm";
+
+/// How the file behind a `MemFd` was created.
+///
+/// See `OpenOptions::fallback`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// A real memfd created by `memfd_create(2)`.
+    MemfdCreate,
+    /// An unnamed file opened with `O_TMPFILE` in `/dev/shm`.
+    TmpFile,
+    /// A file created in `/dev/shm` and unlinked right away.
+    Unlinked,
+}
+
+impl Backend {
+    /// Whether files created through this backend can be sealed.
+    ///
+    /// Only `memfd_create(2)` supports sealing; adding seals to a fallback file fails with
+    /// `Error::SealingUnavailable`.
+    pub fn supports_sealing(&self) -> bool {
+        *self == Backend::MemfdCreate
+    }
+}
+
+/// Creates an anonymous file in `/dev/shm`, trying `O_TMPFILE` before create-then-unlink.
+pub(crate) fn create_fallback(options: &OpenOptions) -> io::Result<(File, Backend)> {
+    let dir = Path::new(SHM_DIR);
+    match open_tmpfile(dir, options) {
+        Ok(file) => Ok((file, Backend::TmpFile)),
+        // Kernels and filesystems without O_TMPFILE report one of these.
+        Err(ref err)
+            if [libc::EISDIR, libc::EOPNOTSUPP, libc::EINVAL]
+                .contains(&err.raw_os_error().unwrap_or(0)) =>
+        {
+            Ok((create_unlinked(dir, options)?, Backend::Unlinked))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn open_tmpfile(dir: &Path, options: &OpenOptions) -> io::Result<File> {
+    let dir = CString::new(dir.as_os_str().as_bytes())?;
+    let flags = libc::O_TMPFILE | libc::O_RDWR | libc::O_EXCL | cloexec(options);
+    let fd = unsafe { libc::open(dir.as_ptr(), flags, mode(options)) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn create_unlinked(dir: &Path, options: &OpenOptions) -> io::Result<File> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let flags = libc::O_CREAT | libc::O_EXCL | libc::O_RDWR | libc::O_NOFOLLOW | cloexec(options);
+    loop {
+        let name = format!(
+            ".memfd-{}-{}",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = CString::new(dir.join(name).as_os_str().as_bytes())?;
+        let fd = unsafe { libc::open(path.as_ptr(), flags, mode(options)) };
+        if fd < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EEXIST) {
+                continue;
+            }
+            return Err(err);
+        }
+
+        let file = unsafe { File::from_raw_fd(fd) };
+        if unsafe { libc::unlink(path.as_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(file);
+    }
+}
+
+fn cloexec(options: &OpenOptions) -> libc::c_int {
+    if options.is_close_on_exec() {
+        libc::O_CLOEXEC
+    } else {
+        0
+    }
+}
+
+fn mode(options: &OpenOptions) -> libc::mode_t {
+    if options.is_exec() {
+        0o700
+    } else {
+        0o600
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::os::unix::fs::{FileExt, MetadataExt};
+    use {Error, MemFd, Seal};
+
+    fn roundtrip(mut file: File) {
+        assert_eq!(0, file.metadata().unwrap().nlink());
+        file.write_all(b"fallback").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut data = String::new();
+        file.read_to_string(&mut data).unwrap();
+        assert_eq!("fallback", data);
+    }
+
+    #[test]
+    fn tmpfile_and_unlinked() {
+        let options = OpenOptions::new();
+        match open_tmpfile(Path::new(SHM_DIR), &options) {
+            Ok(file) => roundtrip(file),
+            Err(err) => assert!(err.raw_os_error().is_some()),
+        }
+
+        let dir = env::temp_dir();
+        roundtrip(create_unlinked(&dir, &options).unwrap());
+        roundtrip(create_unlinked(&dir, &options).unwrap());
+    }
+
+    #[test]
+    fn reports_backend() {
+        let (file, backend) = create_fallback(&OpenOptions::new()).unwrap();
+        assert!(backend != Backend::MemfdCreate);
+        assert!(!backend.supports_sealing());
+        roundtrip(file);
+    }
+
+    #[test]
+    fn fallback_cannot_be_sealed() {
+        let mut options = OpenOptions::new();
+        options.allow_sealing(true).fallback(true);
+        let (file, backend) = create_fallback(&options).unwrap();
+        let memfd = MemFd::new(file, CString::new("fallback").unwrap(), options, backend);
+
+        assert_eq!(backend, memfd.backend());
+        match memfd.add_seal(Seal::Write) {
+            Err(Error::SealingUnavailable(reported)) => assert_eq!(backend, reported),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn fallback_maps_without_seals() {
+        // The temporary directory need not be a tmpfs, where F_GET_SEALS fails.
+        let file = create_unlinked(&env::temp_dir(), &OpenOptions::new()).unwrap();
+        let name = CString::new("fallback").unwrap();
+        let memfd = MemFd::new(file, name, OpenOptions::new(), Backend::Unlinked);
+        memfd.set_len(4096).unwrap();
+
+        let map = memfd.map_mut().unwrap();
+        map.write_at(0, b"mapped").unwrap();
+        let mut data = [0; 6];
+        memfd.as_file().read_exact_at(&mut data, 0).unwrap();
+        assert_eq!(b"mapped", &data);
+    }
+
+    #[test]
+    fn memfd_create_preferred() {
+        let fd = OpenOptions::new()
+            .fallback(true)
+            .create("preferred")
+            .unwrap();
+        assert_eq!(Backend::MemfdCreate, fd.backend());
+    }
+}
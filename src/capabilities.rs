@@ -0,0 +1,120 @@
+use libc;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::OnceLock;
+
+use memfd_create;
+
+/// The memfd features supported by the running kernel, as returned by `features`.
+///
+/// Each feature is probed the first time it is queried and the result is cached for the
+/// lifetime of the process. Features that are never queried are never probed, so a seccomp
+/// policy that forbids, say, `memfd_secret(2)` does not affect programs that do not use it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    _private: (),
+}
+
+impl Capabilities {
+    /// Whether `memfd_create(2)` is available at all (Linux 3.17).
+    pub fn memfd_create(&self) -> bool {
+        static MEMFD_CREATE: OnceLock<bool> = OnceLock::new();
+        *MEMFD_CREATE.get_or_init(|| probe_create(libc::MFD_CLOEXEC).is_ok())
+    }
+
+    /// Whether memfds can be backed by huge pages with `MFD_HUGETLB` (Linux 4.14).
+    ///
+    /// Creation can still fail with `Error::NoHugePages` if none are reserved.
+    pub fn hugetlb(&self) -> bool {
+        static HUGETLB: OnceLock<bool> = OnceLock::new();
+        *HUGETLB.get_or_init(|| probe_create(libc::MFD_CLOEXEC | libc::MFD_HUGETLB).is_ok())
+    }
+
+    /// Whether `Seal::FutureWrite` is supported (Linux 5.1).
+    pub fn future_write_seal(&self) -> bool {
+        static FUTURE_WRITE_SEAL: OnceLock<bool> = OnceLock::new();
+        *FUTURE_WRITE_SEAL.get_or_init(|| {
+            match probe_create(libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING) {
+                Ok(file) => unsafe {
+                    libc::fcntl(
+                        file.as_raw_fd(),
+                        libc::F_ADD_SEALS,
+                        libc::F_SEAL_FUTURE_WRITE,
+                    ) == 0
+                },
+                Err(_) => false,
+            }
+        })
+    }
+
+    /// Whether `MFD_NOEXEC_SEAL`, `MFD_EXEC` and `Seal::Exec` are supported (Linux 6.3).
+    ///
+    /// Without them `OpenOptions::noexec_seal` falls back to clearing the executable bits.
+    pub fn noexec_seal(&self) -> bool {
+        static NOEXEC_SEAL: OnceLock<bool> = OnceLock::new();
+        *NOEXEC_SEAL.get_or_init(|| probe_create(libc::MFD_CLOEXEC | libc::MFD_NOEXEC_SEAL).is_ok())
+    }
+
+    /// Whether `memfd_secret(2)` is available (Linux 5.14, booted with `secretmem.enable=1`
+    /// on kernels before 6.5).
+    pub fn memfd_secret(&self) -> bool {
+        static MEMFD_SECRET: OnceLock<bool> = OnceLock::new();
+        *MEMFD_SECRET.get_or_init(|| {
+            let fd = unsafe { libc::syscall(libc::SYS_memfd_secret, libc::O_CLOEXEC) };
+            if fd >= 0 {
+                unsafe { libc::close(fd as libc::c_int) };
+            }
+            fd >= 0
+        })
+    }
+}
+
+/// Returns the memfd features of the running kernel.
+///
+/// Nothing is probed until a feature is queried. Each query creates at most one short-lived
+/// memfd on its first call; later calls return the cached result.
+///
+/// ## Example
+///
+/// ```
+/// if !memfd::features().hugetlb() {
+///     println!("huge pages are not supported, falling back to regular pages");
+/// }
+/// ```
+pub fn features() -> Capabilities {
+    Capabilities { _private: () }
+}
+
+fn probe_create(flags: libc::c_uint) -> io::Result<File> {
+    let name = CStr::from_bytes_with_nul(b"memfd-probe\0").unwrap();
+    memfd_create(name, flags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probes_once() {
+        let caps = features();
+        assert!(caps.memfd_create());
+        assert_eq!(caps.hugetlb(), features().hugetlb());
+        assert_eq!(caps.noexec_seal(), features().noexec_seal());
+    }
+
+    #[test]
+    fn probe_matches_seal_support() {
+        let caps = features();
+        let file = probe_create(libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING).unwrap();
+        let res = unsafe {
+            libc::fcntl(
+                file.as_raw_fd(),
+                libc::F_ADD_SEALS,
+                libc::F_SEAL_FUTURE_WRITE,
+            )
+        };
+        assert_eq!(caps.future_write_seal(), res == 0);
+    }
+}
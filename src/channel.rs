@@ -0,0 +1,551 @@
+//! A bounded multi-producer/multi-consumer channel living in a memfd.
+//!
+//! Messages are byte strings of up to a fixed slot size. Senders block while the channel is
+//! full and receivers block while it is empty, using futexes placed in the memfd itself, so
+//! both sides may be spread over any number of processes.
+//!
+//! Handles in other processes are reconstructed from the memfd with `Sender::from_memfd` and
+//! `Receiver::from_memfd`. Once every sender is dropped, receivers get `BrokenPipe` after the
+//! remaining messages are drained; once every receiver is dropped, senders get `BrokenPipe`.
+//! Handles of processes that exit without dropping them keep the channel connected.
+//!
+//! ## Example
+//!
+//! ```
+//! let (tx, rx) = memfd::channel::bounded("jobs", 16, 64).unwrap();
+//! tx.send(b"job").unwrap();
+//! assert_eq!(b"job".to_vec(), rx.recv().unwrap());
+//! ```
+
+use std::io;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use error::Error;
+use futex;
+use memfd::MemFd;
+use mmap::MmapMut;
+use sealing::size_seals;
+use OpenOptions;
+
+const MAGIC: u64 = 0x6d65_6d66_6463_6863; // "memfdchc"
+const CAPACITY_OFFSET: usize = 8;
+const SLOT_SIZE_OFFSET: usize = 16;
+const ENQUEUE_OFFSET: usize = 64;
+const DEQUEUE_OFFSET: usize = 128;
+const SENDERS_OFFSET: usize = 192;
+const RECEIVERS_OFFSET: usize = 196;
+const NOT_EMPTY_OFFSET: usize = 200;
+const NOT_FULL_OFFSET: usize = 204;
+const EMPTY_WAITERS_OFFSET: usize = 208;
+const FULL_WAITERS_OFFSET: usize = 212;
+const HEADER_SIZE: usize = 256;
+const SLOT_DATA_OFFSET: usize = 16;
+
+/// Creates a channel named `name` with `capacity` slots of `slot_size` bytes each.
+///
+/// The capacity is rounded up to a power of two. The memfd is sealed against shrinking and
+/// growing, so no process sharing the channel can make the others fault by truncating it.
+pub fn bounded<S: Into<Vec<u8>>>(
+    name: S,
+    capacity: usize,
+    slot_size: usize,
+) -> Result<(Sender, Receiver), Error> {
+    let capacity = capacity.max(1).next_power_of_two();
+    let stride = slot_stride(slot_size as u64);
+    let memfd = OpenOptions::new()
+        .allow_sealing(true)
+        .close_on_exec(true)
+        .create(name)?;
+    memfd.set_len(HEADER_SIZE as u64 + capacity as u64 * stride)?;
+    memfd.add_seals(&size_seals())?;
+
+    let map = memfd.map_mut()?;
+    unsafe {
+        let base = map.as_ptr() as *mut u8;
+        ptr::write(base as *mut u64, MAGIC);
+        ptr::write(base.add(CAPACITY_OFFSET) as *mut u64, capacity as u64);
+        ptr::write(base.add(SLOT_SIZE_OFFSET) as *mut u64, slot_size as u64);
+        ptr::write(base.add(SENDERS_OFFSET) as *mut u32, 1);
+        ptr::write(base.add(RECEIVERS_OFFSET) as *mut u32, 1);
+    }
+
+    let shared = Arc::new(Shared {
+        memfd,
+        map,
+        capacity: capacity as u64,
+        slot_size: slot_size as u64,
+    });
+    for pos in 0..shared.capacity {
+        shared.seq(pos).store(pos, Ordering::Relaxed);
+    }
+
+    Ok((
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    ))
+}
+
+fn slot_stride(slot_size: u64) -> u64 {
+    (SLOT_DATA_OFFSET as u64 + slot_size + 7) & !7
+}
+
+struct Shared {
+    memfd: MemFd,
+    map: MmapMut,
+    capacity: u64,
+    slot_size: u64,
+}
+
+impl Shared {
+    fn open(memfd: MemFd) -> Result<Shared, Error> {
+        let memfd = memfd.require_seals(&size_seals())?.into_memfd();
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "memfd does not contain a channel");
+
+        let header = memfd.map_range(0, HEADER_SIZE).map_err(|_| invalid())?;
+        let (magic, capacity, slot_size) = unsafe {
+            let base = header.as_ptr();
+            (
+                ptr::read(base as *const u64),
+                ptr::read(base.add(CAPACITY_OFFSET) as *const u64),
+                ptr::read(base.add(SLOT_SIZE_OFFSET) as *const u64),
+            )
+        };
+        let expected_size = capacity
+            .checked_mul(slot_stride(slot_size.min(u32::MAX as u64)))
+            .and_then(|slots| slots.checked_add(HEADER_SIZE as u64));
+        if magic != MAGIC
+            || !capacity.is_power_of_two()
+            || slot_size > u32::MAX as u64
+            || expected_size != Some(memfd.as_file().metadata()?.len())
+        {
+            return Err(invalid().into());
+        }
+
+        let map = memfd.map_mut()?;
+        Ok(Shared {
+            memfd,
+            map,
+            capacity,
+            slot_size,
+        })
+    }
+
+    fn field<T>(&self, offset: usize) -> &T {
+        unsafe { &*(self.map.as_ptr().add(offset) as *const T) }
+    }
+
+    fn slot(&self, pos: u64) -> *mut u8 {
+        let index = pos & (self.capacity - 1);
+        unsafe {
+            (self.map.as_ptr() as *mut u8)
+                .add(HEADER_SIZE + (index * slot_stride(self.slot_size)) as usize)
+        }
+    }
+
+    fn seq(&self, pos: u64) -> &AtomicU64 {
+        unsafe { &*(self.slot(pos) as *const AtomicU64) }
+    }
+
+    fn senders(&self) -> &AtomicU32 {
+        self.field(SENDERS_OFFSET)
+    }
+
+    fn receivers(&self) -> &AtomicU32 {
+        self.field(RECEIVERS_OFFSET)
+    }
+
+    fn not_empty(&self) -> &AtomicU32 {
+        self.field(NOT_EMPTY_OFFSET)
+    }
+
+    fn not_full(&self) -> &AtomicU32 {
+        self.field(NOT_FULL_OFFSET)
+    }
+
+    fn empty_waiters(&self) -> &AtomicU32 {
+        self.field(EMPTY_WAITERS_OFFSET)
+    }
+
+    fn full_waiters(&self) -> &AtomicU32 {
+        self.field(FULL_WAITERS_OFFSET)
+    }
+
+    fn push(&self, msg: &[u8]) -> bool {
+        let enqueue = self.field::<AtomicU64>(ENQUEUE_OFFSET);
+        let mut pos = enqueue.load(Ordering::Relaxed);
+        loop {
+            let seq = self.seq(pos).load(Ordering::Acquire);
+            let diff = seq.wrapping_sub(pos) as i64;
+            if diff == 0 {
+                match enqueue.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                return false;
+            } else {
+                pos = enqueue.load(Ordering::Relaxed);
+            }
+        }
+
+        unsafe {
+            let slot = self.slot(pos);
+            ptr::write(slot.add(8) as *mut u32, msg.len() as u32);
+            ptr::copy_nonoverlapping(msg.as_ptr(), slot.add(SLOT_DATA_OFFSET), msg.len());
+        }
+        self.seq(pos).store(pos.wrapping_add(1), Ordering::Release);
+
+        self.not_empty().fetch_add(1, Ordering::SeqCst);
+        if self.empty_waiters().load(Ordering::SeqCst) > 0 {
+            futex::wake(self.not_empty(), 1);
+        }
+        true
+    }
+
+    fn pop(&self) -> io::Result<Option<Vec<u8>>> {
+        let dequeue = self.field::<AtomicU64>(DEQUEUE_OFFSET);
+        let mut pos = dequeue.load(Ordering::Relaxed);
+        loop {
+            let seq = self.seq(pos).load(Ordering::Acquire);
+            let diff = seq.wrapping_sub(pos.wrapping_add(1)) as i64;
+            if diff == 0 {
+                match dequeue.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                return Ok(None);
+            } else {
+                pos = dequeue.load(Ordering::Relaxed);
+            }
+        }
+
+        let msg = unsafe {
+            let slot = self.slot(pos);
+            let len = ptr::read(slot.add(8) as *const u32) as usize;
+            if len as u64 <= self.slot_size {
+                let mut msg = vec![0; len];
+                ptr::copy_nonoverlapping(slot.add(SLOT_DATA_OFFSET), msg.as_mut_ptr(), len);
+                Some(msg)
+            } else {
+                None
+            }
+        };
+        self.seq(pos)
+            .store(pos.wrapping_add(self.capacity), Ordering::Release);
+
+        self.not_full().fetch_add(1, Ordering::SeqCst);
+        if self.full_waiters().load(Ordering::SeqCst) > 0 {
+            futex::wake(self.not_full(), 1);
+        }
+
+        match msg {
+            Some(msg) => Ok(Some(msg)),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message length exceeds the slot size",
+            )),
+        }
+    }
+}
+
+fn disconnected() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "channel is disconnected")
+}
+
+/// The sending half of a channel.
+///
+/// Cloning a sender registers another sender with the channel.
+pub struct Sender {
+    shared: Arc<Shared>,
+}
+
+impl Sender {
+    /// Reconstructs a sender from the memfd of a channel, typically in another process.
+    ///
+    /// Fails with `Error::MissingSeals` unless the memfd is sealed with `Seal::Shrink` and
+    /// `Seal::Grow`.
+    pub fn from_memfd(memfd: MemFd) -> Result<Sender, Error> {
+        let shared = Shared::open(memfd)?;
+        shared.senders().fetch_add(1, Ordering::SeqCst);
+        Ok(Sender {
+            shared: Arc::new(shared),
+        })
+    }
+
+    /// The memfd backing the channel, to be passed to other processes.
+    pub fn memfd(&self) -> &MemFd {
+        &self.shared.memfd
+    }
+
+    /// The maximum size of a message.
+    pub fn slot_size(&self) -> usize {
+        self.shared.slot_size as usize
+    }
+
+    /// Sends `msg`, blocking while the channel is full.
+    ///
+    /// Fails with `BrokenPipe` if all receivers are gone and with `InvalidInput` if `msg` is
+    /// larger than the slot size.
+    pub fn send(&self, msg: &[u8]) -> io::Result<()> {
+        let shared = &self.shared;
+        loop {
+            let epoch = shared.not_full().load(Ordering::SeqCst);
+            match self.try_send(msg) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                res => return res,
+            }
+
+            shared.full_waiters().fetch_add(1, Ordering::SeqCst);
+            futex::wait(shared.not_full(), epoch, None);
+            shared.full_waiters().fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Sends `msg` without blocking, failing with `WouldBlock` if the channel is full.
+    pub fn try_send(&self, msg: &[u8]) -> io::Result<()> {
+        if msg.len() as u64 > self.shared.slot_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "message is larger than the slot size",
+            ));
+        }
+        if self.shared.receivers().load(Ordering::SeqCst) == 0 {
+            return Err(disconnected());
+        }
+        if self.shared.push(msg) {
+            Ok(())
+        } else {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+}
+
+impl Clone for Sender {
+    fn clone(&self) -> Sender {
+        self.shared.senders().fetch_add(1, Ordering::SeqCst);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        if self.shared.senders().fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shared.not_empty().fetch_add(1, Ordering::SeqCst);
+            futex::wake_all(self.shared.not_empty());
+        }
+    }
+}
+
+/// The receiving half of a channel.
+///
+/// Cloning a receiver registers another receiver with the channel.
+pub struct Receiver {
+    shared: Arc<Shared>,
+}
+
+impl Receiver {
+    /// Reconstructs a receiver from the memfd of a channel, typically in another process.
+    ///
+    /// Fails with `Error::MissingSeals` unless the memfd is sealed with `Seal::Shrink` and
+    /// `Seal::Grow`.
+    pub fn from_memfd(memfd: MemFd) -> Result<Receiver, Error> {
+        let shared = Shared::open(memfd)?;
+        shared.receivers().fetch_add(1, Ordering::SeqCst);
+        Ok(Receiver {
+            shared: Arc::new(shared),
+        })
+    }
+
+    /// The memfd backing the channel, to be passed to other processes.
+    pub fn memfd(&self) -> &MemFd {
+        &self.shared.memfd
+    }
+
+    /// Receives a message, blocking while the channel is empty.
+    ///
+    /// Fails with `BrokenPipe` once all senders are gone and the channel is drained.
+    pub fn recv(&self) -> io::Result<Vec<u8>> {
+        let shared = &self.shared;
+        loop {
+            let epoch = shared.not_empty().load(Ordering::SeqCst);
+            match self.try_recv() {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                res => return res,
+            }
+
+            shared.empty_waiters().fetch_add(1, Ordering::SeqCst);
+            futex::wait(shared.not_empty(), epoch, None);
+            shared.empty_waiters().fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Receives a message without blocking, failing with `WouldBlock` if the channel is empty.
+    pub fn try_recv(&self) -> io::Result<Vec<u8>> {
+        if let Some(msg) = self.shared.pop()? {
+            return Ok(msg);
+        }
+        if self.shared.senders().load(Ordering::SeqCst) > 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        // The last sender may have sent a message right before leaving.
+        match self.shared.pop()? {
+            Some(msg) => Ok(msg),
+            None => Err(disconnected()),
+        }
+    }
+}
+
+impl Clone for Receiver {
+    fn clone(&self) -> Receiver {
+        self.shared.receivers().fetch_add(1, Ordering::SeqCst);
+        Receiver {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        if self.shared.receivers().fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shared.not_full().fetch_add(1, Ordering::SeqCst);
+            futex::wake_all(self.shared.not_full());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc;
+    use std::mem;
+    use std::thread;
+    use test_util::pass_memfd;
+
+    #[test]
+    fn send_and_receive() {
+        let (tx, rx) = bounded("channel", 4, 16).unwrap();
+        tx.send(b"hello").unwrap();
+        tx.send(b"").unwrap();
+        assert_eq!(b"hello".to_vec(), rx.recv().unwrap());
+        assert_eq!(Vec::<u8>::new(), rx.recv().unwrap());
+        assert_eq!(io::ErrorKind::WouldBlock, rx.try_recv().unwrap_err().kind());
+    }
+
+    #[test]
+    fn full_and_oversized() {
+        let (tx, _rx) = bounded("channel", 2, 4).unwrap();
+        tx.try_send(b"1").unwrap();
+        tx.try_send(b"2").unwrap();
+        assert_eq!(io::ErrorKind::WouldBlock, tx.try_send(b"3").unwrap_err().kind());
+        assert_eq!(io::ErrorKind::InvalidInput, tx.try_send(b"12345").unwrap_err().kind());
+    }
+
+    #[test]
+    fn disconnect() {
+        let (tx, rx) = bounded("channel", 4, 16).unwrap();
+        tx.send(b"last").unwrap();
+        drop(tx);
+        assert_eq!(b"last".to_vec(), rx.recv().unwrap());
+        assert_eq!(io::ErrorKind::BrokenPipe, rx.recv().unwrap_err().kind());
+
+        let (tx, rx) = bounded("channel", 4, 16).unwrap();
+        drop(rx);
+        assert_eq!(io::ErrorKind::BrokenPipe, tx.send(b"lost").unwrap_err().kind());
+    }
+
+    #[test]
+    fn from_memfd_requires_size_seals() {
+        let (tx, _rx) = bounded("channel", 4, 16).unwrap();
+        assert!(tx.memfd().seals().unwrap().is_superset(&size_seals()));
+
+        let memfd = ::create("plain").unwrap();
+        memfd.set_len(4096).unwrap();
+        match Receiver::from_memfd(memfd) {
+            Err(Error::MissingSeals(missing)) => assert_eq!(size_seals(), missing),
+            Err(other) => panic!("unexpected error: {:?}", other),
+            Ok(_) => panic!("opened an unsealed memfd"),
+        }
+    }
+
+    #[test]
+    fn blocking_send_wakes_up() {
+        let (tx, rx) = bounded("channel", 2, 8).unwrap();
+        let receiver = Receiver::from_memfd(pass_memfd(rx.memfd())).unwrap();
+        drop(rx);
+
+        let handle = thread::spawn(move || {
+            let mut received = 0u64;
+            while let Ok(msg) = receiver.recv() {
+                assert_eq!(&received.to_ne_bytes()[..], &msg[..]);
+                received += 1;
+            }
+            received
+        });
+
+        for i in 0..1000u64 {
+            tx.send(&i.to_ne_bytes()).unwrap();
+        }
+        drop(tx);
+        assert_eq!(1000, handle.join().unwrap());
+    }
+
+    #[test]
+    fn producers_across_fork() {
+        const PER_CHILD: u64 = 1000;
+        let (tx, rx) = bounded("channel", 8, 8).unwrap();
+
+        let mut children = Vec::new();
+        for _ in 0..2 {
+            let child_tx = Sender::from_memfd(pass_memfd(tx.memfd())).unwrap();
+            let pid = unsafe { libc::fork() };
+            if pid == 0 {
+                for i in 0..PER_CHILD {
+                    if child_tx.send(&i.to_ne_bytes()).is_err() {
+                        unsafe { libc::_exit(1) };
+                    }
+                }
+                drop(child_tx);
+                unsafe { libc::_exit(0) };
+            }
+            assert!(pid > 0);
+            // The child owns this registration and releases it when it is done.
+            mem::forget(child_tx);
+            children.push(pid);
+        }
+        drop(tx);
+
+        let mut sum = 0;
+        let mut count = 0;
+        while let Ok(msg) = rx.recv() {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&msg);
+            sum += u64::from_ne_bytes(bytes);
+            count += 1;
+        }
+        assert_eq!(2 * PER_CHILD, count);
+        assert_eq!(PER_CHILD * (PER_CHILD - 1), sum);
+
+        for pid in children {
+            let mut status = 0;
+            unsafe { libc::waitpid(pid, &mut status, 0) };
+            assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+        }
+    }
+}
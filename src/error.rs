@@ -0,0 +1,122 @@
+use std::error;
+use std::ffi::NulError;
+use std::fmt;
+use std::io;
+
+use backend::Backend;
+use hugetlb::HugetlbSize;
+use sealing::SealsHashSet;
+use {OpenOptions, MAX_NAME_LEN};
+
+/// Errors returned by memfd operations.
+#[derive(Debug)]
+pub enum Error {
+    /// The name passed to `create` contains a NUL byte; the error holds the name and position.
+    InteriorNul(NulError),
+    /// The name passed to `create` is longer than `MAX_NAME_LEN` bytes; the name is returned.
+    NameTooLong(Vec<u8>),
+    /// The kernel rejected the flags of the options passed to `create`, e.g. `MFD_HUGETLB` or
+    /// a huge page size it does not support, or the options cannot be combined, as with
+    /// `allow_sealing` and `fallback`.
+    UnsupportedFlags(OpenOptions),
+    /// The memfd was created without `allow_sealing(true)`, so no seals can ever be added.
+    SealingNotAllowed,
+    /// The memfd was created through a fallback backend, which does not support sealing.
+    SealingUnavailable(Backend),
+    /// `Seal::Seal` has already been applied; the set of seals can no longer change.
+    SealLocked,
+    /// A writable mapping was requested for a memfd sealed with `Seal::Write` or
+    /// `Seal::FutureWrite`.
+    WriteSealed,
+    /// The memfd lacks some of the required seals, which are listed.
+    MissingSeals(SealsHashSet),
+    /// No huge pages of the requested size are reserved or available through overcommit.
+    NoHugePages(HugetlbSize),
+    /// `memfd_secret(2)` is unavailable: the kernel was built without `CONFIG_SECRETMEM` or, before
+    /// Linux 6.5, booted without `secretmem.enable=1`.
+    SecretMemUnavailable,
+    /// Any other error reported by the operating system.
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InteriorNul(ref err) => write!(
+                f,
+                "memfd name contains a NUL byte at position {}",
+                err.nul_position()
+            ),
+            Error::NameTooLong(ref name) => write!(
+                f,
+                "memfd name is {} bytes long, at most {} are allowed",
+                name.len(),
+                MAX_NAME_LEN
+            ),
+            Error::UnsupportedFlags(ref options) => write!(
+                f,
+                "the memfd flags {:#x} are not supported",
+                options.bits()
+            ),
+            Error::SealingNotAllowed => {
+                write!(f, "sealing was not allowed when the memfd was created")
+            }
+            Error::SealingUnavailable(backend) => write!(
+                f,
+                "sealing is unavailable for files created through the {:?} fallback",
+                backend
+            ),
+            Error::SealLocked => write!(f, "seals are locked by F_SEAL_SEAL"),
+            Error::WriteSealed => write!(f, "the memfd is sealed against writing"),
+            Error::MissingSeals(ref missing) => {
+                let mut missing: Vec<_> = missing.iter().map(|seal| format!("{:?}", seal)).collect();
+                missing.sort();
+                write!(f, "the memfd is missing required seals: {}", missing.join(", "))
+            }
+            Error::NoHugePages(size) => write!(
+                f,
+                "no {}kB huge pages are available, see /sys/kernel/mm/hugepages",
+                size.page_size() / 1024
+            ),
+            Error::SecretMemUnavailable => write!(
+                f,
+                "memfd_secret(2) is unavailable, boot the kernel with secretmem.enable=1"
+            ),
+            Error::Io(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::InteriorNul(ref err) => Some(err),
+            Error::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        let kind = match err {
+            Error::Io(err) => return err,
+            Error::InteriorNul(_) | Error::NameTooLong(_) => io::ErrorKind::InvalidInput,
+            Error::UnsupportedFlags(_)
+            | Error::SealingUnavailable(_)
+            | Error::SecretMemUnavailable => io::ErrorKind::Unsupported,
+            Error::SealingNotAllowed
+            | Error::SealLocked
+            | Error::WriteSealed
+            | Error::MissingSeals(_) => io::ErrorKind::PermissionDenied,
+            Error::NoHugePages(_) => io::ErrorKind::OutOfMemory,
+        };
+        io::Error::new(kind, err)
+    }
+}
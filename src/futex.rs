@@ -0,0 +1,47 @@
+//! Thin wrappers around `futex(2)` for words living in shared memory.
+//!
+//! `FUTEX_PRIVATE_FLAG` is never used, so waiters and wakers may be in different processes
+//! mapping the same memfd.
+
+use libc;
+use std::io;
+use std::ptr;
+use std::sync::atomic::AtomicU32;
+use std::time::Duration;
+
+/// Sleeps while `word` holds `expected`, at most for `timeout`.
+///
+/// Returns `false` if the timeout expired. Spurious wake-ups are possible, so callers have to
+/// re-check their condition.
+pub fn wait(word: &AtomicU32, expected: u32, timeout: Option<Duration>) -> bool {
+    let timespec = timeout.map(|timeout| libc::timespec {
+        tv_sec: timeout.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    });
+    let timespec_ptr = timespec
+        .as_ref()
+        .map_or(ptr::null(), |timespec| timespec as *const libc::timespec);
+
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word.as_ptr(),
+            libc::FUTEX_WAIT,
+            expected,
+            timespec_ptr,
+        )
+    };
+    !(res < 0 && io::Error::last_os_error().raw_os_error() == Some(libc::ETIMEDOUT))
+}
+
+/// Wakes up to `count` waiters sleeping on `word`.
+pub fn wake(word: &AtomicU32, count: i32) {
+    unsafe {
+        libc::syscall(libc::SYS_futex, word.as_ptr(), libc::FUTEX_WAKE, count);
+    }
+}
+
+/// Wakes all waiters sleeping on `word`.
+pub fn wake_all(word: &AtomicU32) {
+    wake(word, i32::MAX)
+}
@@ -0,0 +1,115 @@
+use libc;
+use std::fs;
+
+/// Huge page size used by a hugetlb-backed memfd.
+///
+/// Which sizes are usable depends on the CPU architecture and on the pages reserved through
+/// `/sys/kernel/mm/hugepages`. See
+/// [`hugetlbpage.txt`](https://www.kernel.org/doc/Documentation/vm/hugetlbpage.txt).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HugetlbSize {
+    /// 64KB huge pages.
+    Huge64KB,
+    /// 512KB huge pages.
+    Huge512KB,
+    /// 1MB huge pages.
+    Huge1MB,
+    /// 2MB huge pages.
+    Huge2MB,
+    /// 8MB huge pages.
+    Huge8MB,
+    /// 16MB huge pages.
+    Huge16MB,
+    /// 32MB huge pages.
+    Huge32MB,
+    /// 256MB huge pages.
+    Huge256MB,
+    /// 512MB huge pages.
+    Huge512MB,
+    /// 1GB huge pages.
+    Huge1GB,
+    /// 2GB huge pages.
+    Huge2GB,
+    /// 16GB huge pages.
+    Huge16GB,
+}
+
+impl HugetlbSize {
+    /// The `MFD_HUGE_*` flag selecting this page size.
+    pub fn bits(self) -> libc::c_uint {
+        match self {
+            HugetlbSize::Huge64KB => libc::MFD_HUGE_64KB,
+            HugetlbSize::Huge512KB => libc::MFD_HUGE_512KB,
+            HugetlbSize::Huge1MB => libc::MFD_HUGE_1MB,
+            HugetlbSize::Huge2MB => libc::MFD_HUGE_2MB,
+            HugetlbSize::Huge8MB => libc::MFD_HUGE_8MB,
+            HugetlbSize::Huge16MB => libc::MFD_HUGE_16MB,
+            HugetlbSize::Huge32MB => libc::MFD_HUGE_32MB,
+            HugetlbSize::Huge256MB => libc::MFD_HUGE_256MB,
+            HugetlbSize::Huge512MB => libc::MFD_HUGE_512MB,
+            HugetlbSize::Huge1GB => libc::MFD_HUGE_1GB,
+            HugetlbSize::Huge2GB => libc::MFD_HUGE_2GB,
+            HugetlbSize::Huge16GB => libc::MFD_HUGE_16GB,
+        }
+    }
+
+    /// The page size in bytes.
+    pub fn page_size(self) -> u64 {
+        1 << ((self.bits() >> libc::MFD_HUGE_SHIFT) & libc::MFD_HUGE_MASK)
+    }
+
+    /// Rounds `len` up to a multiple of the page size.
+    ///
+    /// The length of a hugetlb-backed memfd must be a multiple of its page size, so use this
+    /// before calling `set_len`.
+    pub fn round_up(self, len: u64) -> u64 {
+        let mask = self.page_size() - 1;
+        (len + mask) & !mask
+    }
+
+    /// Rounds `len` down to a multiple of the page size.
+    pub fn round_down(self, len: u64) -> u64 {
+        len & !(self.page_size() - 1)
+    }
+
+    /// Number of pages of this size that can currently be allocated, including overcommit.
+    ///
+    /// Returns `None` if the kernel does not support this page size or its counters in `/sys`
+    /// cannot be read.
+    pub fn available_pages(self) -> Option<u64> {
+        let dir = format!("/sys/kernel/mm/hugepages/hugepages-{}kB", self.page_size() / 1024);
+        let read = |name: &str| -> Option<u64> {
+            fs::read_to_string(format!("{}/{}", dir, name))
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+        };
+
+        let free = read("free_hugepages")?;
+        let overcommit = read("nr_overcommit_hugepages").unwrap_or(0);
+        let surplus = read("surplus_hugepages").unwrap_or(0);
+        Some(free + overcommit.saturating_sub(surplus))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_sizes() {
+        assert_eq!(64 * 1024, HugetlbSize::Huge64KB.page_size());
+        assert_eq!(2 * 1024 * 1024, HugetlbSize::Huge2MB.page_size());
+        assert_eq!(1024 * 1024 * 1024, HugetlbSize::Huge1GB.page_size());
+        assert_eq!(16 * 1024 * 1024 * 1024, HugetlbSize::Huge16GB.page_size());
+    }
+
+    #[test]
+    fn rounding() {
+        let size = HugetlbSize::Huge2MB;
+        assert_eq!(0, size.round_up(0));
+        assert_eq!(2 << 20, size.round_up(1));
+        assert_eq!(2 << 20, size.round_up(2 << 20));
+        assert_eq!(4 << 20, size.round_up((2 << 20) + 1));
+        assert_eq!(2 << 20, size.round_down((4 << 20) - 1));
+    }
+}
@@ -8,7 +8,8 @@
 //!
 //! For a detailed documentation see [the man page for `memfd_create(2)`](http://man7.org/linux/man-pages/man2/memfd_create.2.html).
 //!
-//! This library provides a small convenience wrapper around the syscall to open it as a regular file.
+//! This library provides a small convenience wrapper around the syscall to open it as a `MemFd`,
+//! which can be used like a regular file.
 //!
 //! ## Example
 //!
@@ -18,24 +19,106 @@
 //!
 //! fd.write_all(&b"Hello Rust!"[..]).unwrap();
 //! ```
+//!
+//! ## Sealing
+//!
+//! Files created with `allow_sealing(true)` can be sealed against further modification:
+//!
+//! ```
+//! use memfd::{OpenOptions, Seal};
+//! let fd = OpenOptions::new().allow_sealing(true).create("sealed").unwrap();
+//!
+//! fd.add_seal(Seal::Shrink).unwrap();
+//! assert!(fd.seals().unwrap().contains(&Seal::Shrink));
+//! ```
+
+#![cfg_attr(feature = "nightly", feature(allocator_api))]
+
+extern crate libc;
+#[cfg(feature = "serde")]
+extern crate bincode;
+#[cfg(feature = "rkyv")]
+extern crate rkyv;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(feature = "serde")]
+extern crate serde;
 
-extern crate nix;
+pub mod alloc;
+#[cfg(feature = "rkyv")]
+mod archive;
+#[cfg(feature = "tokio")]
+pub mod async_io;
+mod backend;
+mod capabilities;
+pub mod channel;
+mod error;
+mod futex;
+mod hugetlb;
+mod memfd;
+mod mmap;
+mod noexec;
+pub mod ring;
+mod sealed;
+mod sealing;
+pub mod secret;
+#[cfg(feature = "serde")]
+mod serialize;
+mod shared;
+mod sigbus;
+mod socket;
+pub mod sync;
+#[cfg(test)]
+mod test_util;
+
+#[cfg(feature = "rkyv")]
+pub use archive::{to_archive, ArchivedMemFd};
+pub use backend::Backend;
+pub use capabilities::{features, Capabilities};
+pub use error::Error;
+pub use hugetlb::HugetlbSize;
+pub use memfd::MemFd;
+pub use mmap::{Mmap, MmapMut, SealedMmap};
+pub use noexec::{noexec_policy, NoexecPolicy};
+pub use sealed::{SealedBuffer, SealedBufferBuilder, VerifiedMemFd};
+pub use sealing::{Seal, SealExt, SealsHashSet};
+#[cfg(feature = "serde")]
+pub use serialize::{from_memfd, to_memfd, to_sealed_memfd};
+pub use shared::{Pod, SharedBox, SharedSlice};
+pub use socket::{recv_memfd, recv_memfds, send_memfd, send_memfds, MAX_FDS};
 
-use nix::sys::memfd::*;
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
 use std::fs::File;
 use std::io::{self};
-use std::os::unix::io::FromRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 
-pub struct OpenOptions(MemFdCreateFlag);
+/// The longest name `memfd_create(2)` accepts, in bytes.
+pub const MAX_NAME_LEN: usize = 249;
+
+#[derive(Clone, Copy, Debug)]
+pub struct OpenOptions {
+    flags: libc::c_uint,
+    hugetlb: Option<HugetlbSize>,
+    fallback: bool,
+    lock: bool,
+    dont_fork: bool,
+    zeroize: bool,
+}
 
 /// Options and flags which can be used to configure how a MemFd file is opened.
 impl OpenOptions {
     /// Creates a blank new set of options ready for configuration.
     ///
     /// All options are initially set to `false`.
-    pub fn new() -> OpenOptions {
-        OpenOptions(MemFdCreateFlag::empty())
+    pub const fn new() -> OpenOptions {
+        OpenOptions {
+            flags: 0,
+            hugetlb: None,
+            fallback: false,
+            lock: false,
+            dont_fork: false,
+            zeroize: false,
+        }
     }
 
     /// Allow sealing operations on this file.
@@ -43,37 +126,259 @@ impl OpenOptions {
     /// See [`fcntl(2)`](http://man7.org/linux/man-pages/man2/fcntl.2.html) for available seal
     /// operations.
     pub fn allow_sealing(&mut self, allow_sealing: bool) -> &mut OpenOptions {
-        if allow_sealing {
-            self.0.insert(MFD_ALLOW_SEALING)
-        } else {
-            self.0.remove(MFD_ALLOW_SEALING)
-        }
-        self
+        self.set_flag(libc::MFD_ALLOW_SEALING, allow_sealing)
     }
 
     /// Set the close-on-exec flag on the new file descriptor.
     pub fn close_on_exec(&mut self, cloexec: bool) -> &mut OpenOptions {
-        if cloexec {
-            self.0.insert(MFD_CLOEXEC)
-        } else {
-            self.0.remove(MFD_CLOEXEC)
+        self.set_flag(libc::MFD_CLOEXEC, cloexec)
+    }
+
+    /// Make the file non-executable and seal it against becoming executable (`MFD_NOEXEC_SEAL`).
+    ///
+    /// This implies `allow_sealing(true)` and clears `exec`. On kernels that do not know the flag
+    /// the file is created without it and its executable permission bits are cleared instead.
+    pub fn noexec_seal(&mut self, noexec_seal: bool) -> &mut OpenOptions {
+        if noexec_seal {
+            self.flags &= !libc::MFD_EXEC;
+        }
+        self.set_flag(libc::MFD_NOEXEC_SEAL, noexec_seal)
+    }
+
+    /// Explicitly allow the file to be executable (`MFD_EXEC`).
+    ///
+    /// This clears `noexec_seal`. Creation fails with `PermissionDenied` if the
+    /// `vm.memfd_noexec` sysctl is set to `NoexecPolicy::NoexecEnforced`. On kernels that do not
+    /// know the flag the file is created without it, which makes it executable.
+    pub fn exec(&mut self, exec: bool) -> &mut OpenOptions {
+        if exec {
+            self.flags &= !libc::MFD_NOEXEC_SEAL;
         }
+        self.set_flag(libc::MFD_EXEC, exec)
+    }
+
+    /// Back the file with huge pages of the given size, or with regular pages for `None`.
+    ///
+    /// The length of a hugetlb-backed file must be a multiple of the page size, see
+    /// `HugetlbSize::round_up`. Creation fails with `Error::NoHugePages` if no pages of the
+    /// requested size are reserved.
+    pub fn hugetlb(&mut self, size: Option<HugetlbSize>) -> &mut OpenOptions {
+        self.hugetlb = size;
+        self
+    }
+
+    /// Fall back to an anonymous file in `/dev/shm` if `memfd_create(2)` is unavailable.
+    ///
+    /// The file is opened with `O_TMPFILE`, or created and unlinked right away where that is not
+    /// supported. `MemFd::backend` reports which backend was used. Fallback files cannot be
+    /// sealed, so `create` refuses to combine this option with `allow_sealing(true)`, and
+    /// `Error::SealingUnavailable` is returned when adding seals to them. Huge pages are not
+    /// available either.
+    ///
+    /// Fallback files are not memfds: `MemFd::try_from_fd` and `recv_memfd` reject them with
+    /// `InvalidData`, so they cannot be passed to other processes with `send_memfd`.
+    pub fn fallback(&mut self, fallback: bool) -> &mut OpenOptions {
+        self.fallback = fallback;
+        self
+    }
+
+    /// Lock mappings of the file into memory, for buffers holding secrets.
+    ///
+    /// Every mapping returned by `MemFd::map` and friends is locked with `mlock(2)` and excluded
+    /// from core dumps with `MADV_DONTDUMP`. The mappings are shared, which the kernel cannot wipe
+    /// on fork, so they stay accessible in forked children unless `exclude_from_fork` is set.
+    /// Mapping fails if the lock would exceed `RLIMIT_MEMLOCK`.
+    pub fn lock_in_memory(&mut self, lock: bool) -> &mut OpenOptions {
+        self.lock = lock;
+        self
+    }
+
+    /// Keep locked mappings out of forked children with `MADV_DONTFORK`.
+    ///
+    /// Only takes effect together with `lock_in_memory`. The mappings are absent from children
+    /// forked afterwards, so any access to them in the child faults with `SIGSEGV`. Only use this
+    /// if children never touch them, e.g. because they `exec` right away.
+    pub fn exclude_from_fork(&mut self, exclude: bool) -> &mut OpenOptions {
+        self.dont_fork = exclude;
+        self
+    }
+
+    /// Wipe the contents of the file when the `MemFd` is dropped.
+    ///
+    /// The file is overwritten with zeros and its pages are released with
+    /// `FALLOC_FL_PUNCH_HOLE`, keeping its size. This also affects every other holder of the
+    /// file, including other processes. A write-sealed file cannot be wiped, and files taken out
+    /// with `into_file` or `into_raw_fd` are not wiped.
+    pub fn zeroize_on_drop(&mut self, zeroize: bool) -> &mut OpenOptions {
+        self.zeroize = zeroize;
         self
     }
 
+    /// Whether sealing operations are allowed on files created with these options.
+    pub fn is_sealing_allowed(&self) -> bool {
+        self.flags & (libc::MFD_ALLOW_SEALING | libc::MFD_NOEXEC_SEAL) != 0
+    }
+
+    /// Whether the close-on-exec flag is set on files created with these options.
+    pub fn is_close_on_exec(&self) -> bool {
+        self.flags & libc::MFD_CLOEXEC != 0
+    }
+
+    /// Whether files created with these options are sealed against becoming executable.
+    pub fn is_noexec_seal(&self) -> bool {
+        self.flags & libc::MFD_NOEXEC_SEAL != 0
+    }
+
+    /// Whether files created with these options are explicitly executable.
+    pub fn is_exec(&self) -> bool {
+        self.flags & libc::MFD_EXEC != 0
+    }
+
+    /// The huge page size files created with these options are backed by, if any.
+    pub fn hugetlb_size(&self) -> Option<HugetlbSize> {
+        self.hugetlb
+    }
+
+    /// Whether files may be created through a fallback backend.
+    pub fn is_fallback_allowed(&self) -> bool {
+        self.fallback
+    }
+
+    /// Whether mappings of files created with these options are locked into memory.
+    pub fn is_locked_in_memory(&self) -> bool {
+        self.lock
+    }
+
+    /// Whether locked mappings of files created with these options are kept out of forked
+    /// children.
+    pub fn is_excluded_from_fork(&self) -> bool {
+        self.dont_fork
+    }
+
+    /// Whether files created with these options are wiped when the `MemFd` is dropped.
+    pub fn is_zeroize_on_drop(&self) -> bool {
+        self.zeroize
+    }
+
     /// Creates a memfd file at `name` with the options specified by `self`.
-    pub fn create<S: Into<Vec<u8>>>(&self, name: S) -> io::Result<File> {
-        let name = CString::new(name).unwrap();
-        let rawfd = memfd_create(&name, self.0)?;
+    ///
+    /// `name` must not contain NUL bytes and may be at most `MAX_NAME_LEN` bytes long; otherwise
+    /// `Error::InteriorNul` or `Error::NameTooLong` is returned without calling into the kernel.
+    /// Flags the running kernel does not know are reported as `Error::UnsupportedFlags`; options
+    /// that `features()` already rules out are refused before any memfd is created.
+    pub fn create<S: Into<Vec<u8>>>(&self, name: S) -> Result<MemFd, Error> {
+        let name = CString::new(name).map_err(Error::InteriorNul)?;
+        if name.as_bytes().len() > MAX_NAME_LEN {
+            return Err(Error::NameTooLong(name.into_bytes()));
+        }
+        self.check_supported()?;
+
+        if self.fallback && !features().memfd_create() {
+            let (file, backend) = backend::create_fallback(self)?;
+            return Ok(MemFd::new(file, name, *self, backend));
+        }
+
+        if let Some(size) = self.hugetlb {
+            // Counters that cannot be read, e.g. without /sys, leave the decision to the kernel.
+            if size.available_pages() == Some(0) {
+                return Err(Error::NoHugePages(size));
+            }
+        }
+
+        match self.create_raw(&name) {
+            Ok(file) => Ok(MemFd::new(file, name, *self, Backend::MemfdCreate)),
+            Err(ref err) if err.raw_os_error() == Some(libc::EINVAL) => {
+                Err(Error::UnsupportedFlags(*self))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
 
-        unsafe {
-            Ok(File::from_raw_fd(rawfd))
+    /// Checks these options against the capabilities of the running kernel.
+    ///
+    /// Fails with `ENOSYS` if `memfd_create(2)` is unavailable and `fallback` is not enabled, and
+    /// with `Error::UnsupportedFlags` if huge pages are requested but not supported, or if
+    /// `allow_sealing` is combined with `fallback`, whose files can never be sealed. The exec
+    /// flags are not checked since `create` falls back gracefully on kernels without them. Only
+    /// the capabilities these options depend on are probed.
+    pub fn check_supported(&self) -> Result<(), Error> {
+        // `noexec_seal` alone is fine: fallback files are created without exec permissions.
+        if self.fallback && self.flags & libc::MFD_ALLOW_SEALING != 0 {
+            return Err(Error::UnsupportedFlags(*self));
+        }
+        let caps = features();
+        if !self.fallback && !caps.memfd_create() {
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS).into());
+        }
+        if self.hugetlb.is_some() && !caps.hugetlb() {
+            return Err(Error::UnsupportedFlags(*self));
         }
+        Ok(())
+    }
+
+    /// Creates the file without allocating, so it can be used from within an allocator.
+    pub(crate) fn create_raw(&self, name: &CStr) -> io::Result<File> {
+        let bits = self.bits();
+        let exec_bits = libc::MFD_NOEXEC_SEAL | libc::MFD_EXEC;
+        let file = match memfd_create(name, bits) {
+            // Kernels before 6.3 reject the exec flags as unknown.
+            Err(ref err) if err.raw_os_error() == Some(libc::EINVAL) && bits & exec_bits != 0 => {
+                let mut fallback = bits & !exec_bits;
+                if self.is_noexec_seal() {
+                    fallback |= libc::MFD_ALLOW_SEALING;
+                }
+                let file = memfd_create(name, fallback)?;
+                if self.is_noexec_seal() {
+                    clear_exec_permissions(&file)?;
+                }
+                file
+            }
+            res => res?,
+        };
+        Ok(file)
+    }
+
+    fn set_flag(&mut self, flag: libc::c_uint, value: bool) -> &mut OpenOptions {
+        if value {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+        self
+    }
+
+    pub(crate) fn bits(&self) -> libc::c_uint {
+        match self.hugetlb {
+            Some(size) => self.flags | libc::MFD_HUGETLB | size.bits(),
+            None => self.flags,
+        }
+    }
+}
+
+fn memfd_create(name: &CStr, flags: libc::c_uint) -> io::Result<File> {
+    let rawfd = unsafe { libc::syscall(libc::SYS_memfd_create, name.as_ptr(), flags) };
+    if rawfd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(rawfd as libc::c_int) })
+}
+
+fn clear_exec_permissions(file: &File) -> io::Result<()> {
+    let res = unsafe { libc::fchmod(file.as_raw_fd(), 0o666) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl Default for OpenOptions {
+    fn default() -> OpenOptions {
+        OpenOptions::new()
     }
 }
 
 /// Creates a memfd file at `name`
-pub fn create<S: Into<Vec<u8>>>(name: S) -> io::Result<File> {
+pub fn create<S: Into<Vec<u8>>>(name: S) -> Result<MemFd, Error> {
     OpenOptions::new().create(name)
 }
 
@@ -157,4 +462,78 @@ mod tests {
             .allow_sealing(true)
             .create("foobar").unwrap();
     }
+
+    #[test]
+    fn noexec_seal() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let fd = OpenOptions::new().noexec_seal(true).create("noexec").unwrap();
+        let mode = fd.as_file().metadata().unwrap().permissions().mode();
+        assert_eq!(0, mode & 0o111);
+        if noexec_policy().is_some() {
+            assert!(fd.seals().unwrap().contains(&Seal::Exec));
+        }
+    }
+
+    #[test]
+    fn exec_and_noexec_seal_are_exclusive() {
+        let mut options = OpenOptions::new();
+        options.noexec_seal(true).exec(true);
+        assert!(options.is_exec());
+        assert!(!options.is_noexec_seal());
+
+        if noexec_policy() != Some(NoexecPolicy::NoexecEnforced) {
+            let _fd = options.create("exec").unwrap();
+        }
+    }
+
+    #[test]
+    fn hugetlb_without_reserved_pages() {
+        let size = HugetlbSize::Huge2MB;
+        if size.available_pages() != Some(0) {
+            return;
+        }
+
+        let err = OpenOptions::new().hugetlb(Some(size)).create("huge").unwrap_err();
+        match err {
+            Error::NoHugePages(HugetlbSize::Huge2MB) => {}
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        match create("nul\0byte").unwrap_err() {
+            Error::InteriorNul(err) => {
+                assert_eq!(3, err.nul_position());
+                assert_eq!(b"nul\0byte", &err.into_vec()[..]);
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        let _fd = create(vec![b'a'; MAX_NAME_LEN]).unwrap();
+        match create(vec![b'a'; MAX_NAME_LEN + 1]).unwrap_err() {
+            Error::NameTooLong(name) => assert_eq!(MAX_NAME_LEN + 1, name.len()),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unsupported_flags() {
+        let mut options = OpenOptions::new();
+        options.flags = 1 << 30;
+        match options.create("flags").unwrap_err() {
+            Error::UnsupportedFlags(options) => assert_eq!(1 << 30, options.bits()),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sealing_refused_with_fallback() {
+        match OpenOptions::new().allow_sealing(true).fallback(true).create("fallback") {
+            Err(Error::UnsupportedFlags(options)) => assert!(options.is_fallback_allowed()),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        OpenOptions::new().noexec_seal(true).fallback(true).create("fallback").unwrap();
+    }
 }
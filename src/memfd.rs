@@ -0,0 +1,528 @@
+use libc;
+use std::ffi::{CStr, CString};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::ManuallyDrop;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::ptr;
+
+use backend::Backend;
+use error::Error;
+use mmap::{self, Mmap, MmapMut, SealedMmap};
+use sealed::VerifiedMemFd;
+use sealing::{self, Seal, SealsHashSet};
+use OpenOptions;
+
+/// An anonymous memory-backed file created by `memfd_create(2)`.
+///
+/// `MemFd` behaves like a regular `File` and additionally remembers the name and options it
+/// was created with.
+#[derive(Debug)]
+pub struct MemFd {
+    file: File,
+    name: Option<CString>,
+    options: Option<OpenOptions>,
+    backend: Backend,
+}
+
+impl MemFd {
+    pub(crate) fn new(
+        file: File,
+        name: CString,
+        options: OpenOptions,
+        backend: Backend,
+    ) -> MemFd {
+        MemFd {
+            file,
+            name: Some(name),
+            options: Some(options),
+            backend,
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) fn from_parts(
+        file: File,
+        name: Option<CString>,
+        options: Option<OpenOptions>,
+        backend: Backend,
+    ) -> MemFd {
+        MemFd {
+            file,
+            name,
+            options,
+            backend,
+        }
+    }
+
+    /// Takes the memfd apart without wiping it, see `OpenOptions::zeroize_on_drop`.
+    pub(crate) fn into_parts(self) -> (File, Option<CString>, Option<OpenOptions>, Backend) {
+        let memfd = ManuallyDrop::new(self);
+        // `memfd` is never dropped, so each field is moved out exactly once.
+        unsafe {
+            (
+                ptr::read(&memfd.file),
+                ptr::read(&memfd.name),
+                ptr::read(&memfd.options),
+                memfd.backend,
+            )
+        }
+    }
+
+    /// Takes ownership of `fd` if it refers to a memfd.
+    ///
+    /// The descriptor is checked to support `F_GET_SEALS` and to be linked as `/memfd:<name>`
+    /// in `/proc/self/fd`, so regular files, pipes and sockets are rejected. On failure the
+    /// original descriptor is handed back unchanged.
+    pub fn try_from_fd<F: AsRawFd + IntoRawFd>(fd: F) -> Result<MemFd, F> {
+        match memfd_name(fd.as_raw_fd()) {
+            Some(name) => {
+                let file = unsafe { File::from_raw_fd(fd.into_raw_fd()) };
+                Ok(MemFd {
+                    file,
+                    name: Some(name),
+                    options: None,
+                    backend: Backend::MemfdCreate,
+                })
+            }
+            None => Err(fd),
+        }
+    }
+
+    /// Takes ownership of `file` if it refers to a memfd.
+    ///
+    /// See `try_from_fd` for the checks performed.
+    pub fn try_from_file(file: File) -> Result<MemFd, File> {
+        MemFd::try_from_fd(file)
+    }
+
+    /// The name this memfd was created with.
+    ///
+    /// Returns `None` if the memfd was constructed from a raw file descriptor. For memfds obtained
+    /// through `try_from_fd` the name is recovered from `/proc/self/fd`.
+    pub fn name(&self) -> Option<&CStr> {
+        self.name.as_deref()
+    }
+
+    /// The options this memfd was created with.
+    ///
+    /// Returns `None` if the memfd was constructed from a raw file descriptor.
+    pub fn options(&self) -> Option<&OpenOptions> {
+        self.options.as_ref()
+    }
+
+    /// How the underlying file was created.
+    ///
+    /// This is `Backend::MemfdCreate` unless the memfd was created with `OpenOptions::fallback`
+    /// on a system without `memfd_create(2)`.
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Returns a reference to the underlying file.
+    pub fn as_file(&self) -> &File {
+        &self.file
+    }
+
+    /// Converts this memfd into the underlying file.
+    pub fn into_file(self) -> File {
+        self.into_parts().0
+    }
+
+    /// Truncates or extends the memfd to `size` bytes.
+    pub fn set_len(&self, size: u64) -> io::Result<()> {
+        self.file.set_len(size)
+    }
+
+    /// Adds a single seal.
+    pub fn add_seal(&self, seal: Seal) -> Result<(), Error> {
+        self.check_sealing()?;
+        sealing::add_seals(self.as_raw_fd(), seal.bits(), self.sealing_allowed())
+    }
+
+    /// Adds all seals in `seals` in a single operation.
+    pub fn add_seals(&self, seals: &SealsHashSet) -> Result<(), Error> {
+        self.check_sealing()?;
+        sealing::add_seals(
+            self.as_raw_fd(),
+            sealing::seals_to_bits(seals),
+            self.sealing_allowed(),
+        )
+    }
+
+    /// Returns the seals currently set.
+    pub fn seals(&self) -> Result<SealsHashSet, Error> {
+        Ok(sealing::get_seals(self.as_raw_fd())?)
+    }
+
+    /// Checks that the memfd carries `Seal::Shrink` and at least the seals in `required`.
+    ///
+    /// `Seal::Shrink` is always required, as it is what rules out `SIGBUS` on the mappings of a
+    /// `VerifiedMemFd`. Use this before mapping a memfd received from an untrusted peer. Fails with
+    /// `Error::MissingSeals` listing the seals that are not set, in which case the memfd is
+    /// closed.
+    pub fn require_seals(self, required: &SealsHashSet) -> Result<VerifiedMemFd, Error> {
+        VerifiedMemFd::verify(self, required)
+    }
+
+    /// Maps the whole memfd read-only.
+    ///
+    /// The mapping covers the size of the file at the time of the call.
+    pub fn map(&self) -> Result<Mmap, Error> {
+        let len = self.size()?;
+        self.map_range(0, len)
+    }
+
+    /// Maps the whole memfd read-write.
+    ///
+    /// Fails with `Error::WriteSealed` if the memfd carries `Seal::Write` or `Seal::FutureWrite`.
+    pub fn map_mut(&self) -> Result<MmapMut, Error> {
+        let len = self.size()?;
+        self.map_range_mut(0, len)
+    }
+
+    /// Maps `len` bytes starting at `offset` read-only.
+    ///
+    /// `offset` does not need to be page-aligned, but the range must lie within the file.
+    pub fn map_range(&self, offset: u64, len: usize) -> Result<Mmap, Error> {
+        self.check_range(offset, len)?;
+        let map = Mmap::new(self.as_raw_fd(), offset, len, self.page_size())?;
+        if self.is_locked_in_memory() {
+            map.lock(self.is_excluded_from_fork())?;
+        }
+        Ok(map)
+    }
+
+    /// Maps `len` bytes starting at `offset` read-write.
+    ///
+    /// See `map_range` and `map_mut`.
+    pub fn map_range_mut(&self, offset: u64, len: usize) -> Result<MmapMut, Error> {
+        self.check_range(offset, len)?;
+        // Fallback files may live on a filesystem without F_GET_SEALS; they carry no seals anyway.
+        if self.backend.supports_sealing() {
+            let seals = self.seals()?;
+            if seals.contains(&Seal::Write) || seals.contains(&Seal::FutureWrite) {
+                return Err(Error::WriteSealed);
+            }
+        }
+        let map = MmapMut::new(self.as_raw_fd(), offset, len, self.page_size())?;
+        if self.is_locked_in_memory() {
+            map.lock(self.is_excluded_from_fork())?;
+        }
+        Ok(map)
+    }
+
+    /// Maps the whole memfd read-only, as a slice that can be borrowed safely.
+    ///
+    /// Fails with `Error::MissingSeals` unless the memfd carries `Seal::Shrink` and `Seal::Write`,
+    /// which guarantee that the contents never change or disappear while they are mapped.
+    pub fn map_sealed(&self) -> Result<SealedMmap, Error> {
+        let len = self.size()?;
+        self.map_range_sealed(0, len)
+    }
+
+    /// Maps `len` bytes starting at `offset` read-only, see `map_sealed`.
+    pub fn map_range_sealed(&self, offset: u64, len: usize) -> Result<SealedMmap, Error> {
+        let required: SealsHashSet = [Seal::Shrink, Seal::Write].iter().cloned().collect();
+        let missing: SealsHashSet = required.difference(&self.seals()?).cloned().collect();
+        if !missing.is_empty() {
+            return Err(Error::MissingSeals(missing));
+        }
+        self.check_range(offset, len)?;
+        let map = SealedMmap::new(self.as_raw_fd(), offset, len, self.page_size())?;
+        if self.is_locked_in_memory() {
+            map.lock(self.is_excluded_from_fork())?;
+        }
+        Ok(map)
+    }
+
+    fn size(&self) -> io::Result<usize> {
+        Ok(self.file.metadata()?.len() as usize)
+    }
+
+    fn check_range(&self, offset: u64, len: usize) -> io::Result<()> {
+        let size = self.size()? as u64;
+        if offset.checked_add(len as u64).is_none_or(|end| end > size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "mapping range exceeds the size of the memfd",
+            ));
+        }
+        Ok(())
+    }
+
+    fn page_size(&self) -> usize {
+        match self.options.and_then(|options| options.hugetlb_size()) {
+            Some(size) => size.page_size() as usize,
+            None => mmap::page_size(),
+        }
+    }
+
+    fn sealing_allowed(&self) -> Option<bool> {
+        self.options.as_ref().map(|options| options.is_sealing_allowed())
+    }
+
+    fn is_locked_in_memory(&self) -> bool {
+        self.options.is_some_and(|options| options.is_locked_in_memory())
+    }
+
+    fn is_excluded_from_fork(&self) -> bool {
+        self.options.is_some_and(|options| options.is_excluded_from_fork())
+    }
+
+    fn check_sealing(&self) -> Result<(), Error> {
+        if !self.backend.supports_sealing() {
+            return Err(Error::SealingUnavailable(self.backend));
+        }
+        Ok(())
+    }
+}
+
+/// Overwrites the contents of `file` with zeros, then releases its pages.
+pub(crate) fn zeroize(file: &File) -> io::Result<()> {
+    const ZEROS: [u8; 4096] = [0; 4096];
+
+    let len = file.metadata()?.len();
+    let mut overwritten = Ok(());
+    let mut offset = 0;
+    while offset < len {
+        let n = (len - offset).min(ZEROS.len() as u64) as usize;
+        // hugetlbfs does not support write(2), but its pages can still be released below.
+        if let Err(err) = file.write_all_at(&ZEROS[..n], offset) {
+            overwritten = Err(err);
+            break;
+        }
+        offset += n as u64;
+    }
+
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    if unsafe { libc::fallocate(file.as_raw_fd(), mode, 0, len as libc::off_t) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    overwritten
+}
+
+/// Returns the memfd name of `fd`, or `None` if it is not a memfd.
+fn memfd_name(fd: RawFd) -> Option<CString> {
+    const PREFIX: &[u8] = b"/memfd:";
+    const SUFFIX: &[u8] = b" (deleted)";
+
+    if sealing::get_seals(fd).is_err() {
+        return None;
+    }
+
+    let target = fs::read_link(format!("/proc/self/fd/{}", fd)).ok()?;
+    let target = target.as_os_str().as_bytes();
+    if !target.starts_with(PREFIX) {
+        return None;
+    }
+
+    let name = &target[PREFIX.len()..];
+    let name = if name.ends_with(SUFFIX) {
+        &name[..name.len() - SUFFIX.len()]
+    } else {
+        name
+    };
+    CString::new(name).ok()
+}
+
+impl Read for MemFd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for MemFd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for MemFd {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl AsRawFd for MemFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for MemFd {
+    fn into_raw_fd(self) -> RawFd {
+        self.into_file().into_raw_fd()
+    }
+}
+
+impl Drop for MemFd {
+    fn drop(&mut self) {
+        if self.options.is_some_and(|options| options.is_zeroize_on_drop()) {
+            // Write-sealed files cannot be wiped, and there is no way to report it from here.
+            let _ = zeroize(&self.file);
+        }
+    }
+}
+
+impl FromRawFd for MemFd {
+    unsafe fn from_raw_fd(fd: RawFd) -> MemFd {
+        MemFd {
+            file: File::from_raw_fd(fd),
+            name: None,
+            options: None,
+            backend: Backend::MemfdCreate,
+        }
+    }
+}
+
+impl From<MemFd> for File {
+    fn from(memfd: MemFd) -> File {
+        memfd.into_file()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use create;
+
+    #[test]
+    fn remembers_name_and_options() {
+        let fd = OpenOptions::new().allow_sealing(true).create("named").unwrap();
+        assert_eq!(Some(&b"named"[..]), fd.name().map(|name| name.to_bytes()));
+        assert!(fd.options().unwrap().is_sealing_allowed());
+    }
+
+    #[test]
+    fn raw_fd_roundtrip() {
+        let mut fd = create("roundtrip").unwrap();
+        fd.write_all(b"hello").unwrap();
+
+        let mut fd = unsafe { MemFd::from_raw_fd(fd.into_raw_fd()) };
+        assert!(fd.name().is_none());
+        assert!(fd.options().is_none());
+
+        let mut s = Vec::new();
+        fd.seek(SeekFrom::Start(0)).unwrap();
+        fd.read_to_end(&mut s).unwrap();
+        assert_eq!(b"hello", &s[..]);
+    }
+
+    #[test]
+    fn try_from_file_accepts_memfd() {
+        let file = create("probed").unwrap().into_file();
+        let fd = MemFd::try_from_file(file).unwrap();
+        assert_eq!(Some(&b"probed"[..]), fd.name().map(|name| name.to_bytes()));
+    }
+
+    #[test]
+    fn try_from_file_rejects_regular_file() {
+        let file = File::open("/proc/self/exe").unwrap();
+        let raw = file.as_raw_fd();
+        let file = MemFd::try_from_file(file).unwrap_err();
+        assert_eq!(raw, file.as_raw_fd());
+    }
+
+    #[test]
+    fn try_from_fd_rejects_socket() {
+        use std::os::unix::net::UnixStream;
+        let (a, _b) = UnixStream::pair().unwrap();
+        assert!(MemFd::try_from_fd(a).is_err());
+    }
+
+    #[test]
+    fn sealing_not_allowed_is_known() {
+        let fd = create("unsealable").unwrap();
+        match fd.add_seal(Seal::Seal) {
+            Err(Error::SealingNotAllowed) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn zeroizes_on_drop() {
+        use std::os::unix::fs::MetadataExt;
+
+        let len = 3 * mmap::page_size();
+        let mut fd = OpenOptions::new().zeroize_on_drop(true).create("token").unwrap();
+        fd.write_all(&vec![0xaa; len]).unwrap();
+        let copy = fd.as_file().try_clone().unwrap();
+        drop(fd);
+
+        assert_eq!(len as u64, copy.metadata().unwrap().len());
+        assert_eq!(0, copy.metadata().unwrap().blocks());
+        let mut data = vec![0xff; len];
+        copy.read_exact_at(&mut data, 0).unwrap();
+        assert!(data.iter().all(|&b| b == 0));
+
+        let mut fd = OpenOptions::new().zeroize_on_drop(true).create("kept").unwrap();
+        fd.write_all(b"kept").unwrap();
+        let file = fd.into_file();
+        let mut data = [0; 4];
+        file.read_exact_at(&mut data, 0).unwrap();
+        assert_eq!(b"kept", &data);
+    }
+
+    #[test]
+    fn locks_mappings() {
+        fn locked_kb() -> u64 {
+            let status = fs::read_to_string("/proc/self/status").unwrap();
+            let line = status.lines().find(|line| line.starts_with("VmLck:")).unwrap();
+            line.split_whitespace().nth(1).unwrap().parse().unwrap()
+        }
+
+        // Leave room for the other tests locking memory while this one runs.
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) };
+        if limit.rlim_cur < 64 * 1024 {
+            return;
+        }
+
+        let fd = OpenOptions::new().lock_in_memory(true).create("locked").unwrap();
+        fd.set_len(16 * 1024).unwrap();
+        let map = fd.map_mut().unwrap();
+        assert!(locked_kb() >= 16);
+        drop(map);
+    }
+
+    #[test]
+    fn locked_mappings_survive_fork() {
+        fn child_reads(options: &OpenOptions) -> libc::c_int {
+            let fd = options.create("locked").unwrap();
+            fd.set_len(4096).unwrap();
+            let map = fd.map().unwrap();
+
+            let pid = unsafe { libc::fork() };
+            if pid == 0 {
+                let limit = libc::rlimit {
+                    rlim_cur: 0,
+                    rlim_max: 0,
+                };
+                unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) };
+                unsafe { ptr::read_volatile(map.as_ptr()) };
+                unsafe { libc::_exit(0) };
+            }
+            assert!(pid > 0);
+            let mut status = 0;
+            unsafe { libc::waitpid(pid, &mut status, 0) };
+            status
+        }
+
+        let status = child_reads(OpenOptions::new().lock_in_memory(true));
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+
+        let status = child_reads(OpenOptions::new().lock_in_memory(true).exclude_from_fork(true));
+        assert!(libc::WIFSIGNALED(status));
+        assert_eq!(libc::SIGSEGV, libc::WTERMSIG(status));
+    }
+}
@@ -0,0 +1,468 @@
+use libc;
+use std::io;
+use std::ops::Deref;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use sigbus;
+
+/// Size of a regular memory page.
+pub fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+struct MmapInner {
+    ptr: *mut libc::c_void,
+    map_len: usize,
+    data: *mut u8,
+    len: usize,
+    align: usize,
+    truncated: AtomicBool,
+}
+
+impl MmapInner {
+    fn new(
+        fd: RawFd,
+        offset: u64,
+        len: usize,
+        align: usize,
+        prot: libc::c_int,
+    ) -> io::Result<MmapInner> {
+        if len == 0 {
+            return Ok(MmapInner {
+                ptr: ptr::null_mut(),
+                map_len: 0,
+                data: ptr::NonNull::dangling().as_ptr(),
+                len: 0,
+                align,
+                truncated: AtomicBool::new(false),
+            });
+        }
+
+        let delta = (offset % align as u64) as usize;
+        let map_len = len + delta;
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                map_len,
+                prot,
+                libc::MAP_SHARED,
+                fd,
+                (offset - delta as u64) as libc::off_t,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(MmapInner {
+            ptr,
+            map_len,
+            data: unsafe { (ptr as *mut u8).add(delta) },
+            len,
+            align,
+            truncated: AtomicBool::new(false),
+        })
+    }
+
+    /// Locks the mapping into memory and keeps it out of core dumps. With `dont_fork`, it is not
+    /// inherited by forked children either.
+    fn lock(&self, dont_fork: bool) -> io::Result<()> {
+        if self.map_len == 0 {
+            return Ok(());
+        }
+        if unsafe { libc::mlock(self.ptr, self.map_len) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        madvise(self.ptr, self.map_len, libc::MADV_DONTDUMP)?;
+        // Shared mappings cannot be marked `MADV_WIPEONFORK`, so hiding them from the child is
+        // the only option, and one that makes any access there fault.
+        if dont_fork {
+            madvise(self.ptr, self.map_len, libc::MADV_DONTFORK)?;
+        }
+        Ok(())
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> io::Result<usize> {
+        if offset >= self.len {
+            return self.check_truncated().map(|_| 0);
+        }
+        let n = buf.len().min(self.len - offset);
+        let src = unsafe { self.data.add(offset) };
+        self.guarded(|| unsafe { ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), n) })?;
+        Ok(n)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> io::Result<usize> {
+        if offset >= self.len {
+            return self.check_truncated().map(|_| 0);
+        }
+        let n = buf.len().min(self.len - offset);
+        let dst = unsafe { self.data.add(offset) };
+        self.guarded(|| unsafe { ptr::copy_nonoverlapping(buf.as_ptr(), dst, n) })?;
+        Ok(n)
+    }
+
+    fn guarded<F: FnOnce()>(&self, f: F) -> io::Result<()> {
+        self.check_truncated()?;
+        if !sigbus::guarded(self.ptr as *const u8, self.map_len, self.align, f) {
+            self.truncated.store(true, Ordering::Relaxed);
+            return Err(truncated_error());
+        }
+        Ok(())
+    }
+
+    fn check_truncated(&self) -> io::Result<()> {
+        if self.truncated.load(Ordering::Relaxed) {
+            return Err(truncated_error());
+        }
+        Ok(())
+    }
+
+    fn slice(&self) -> &[u8] {
+        self.assert_intact();
+        unsafe { slice::from_raw_parts(self.data, self.len) }
+    }
+
+    /// Once a fault was recovered from, parts of the mapping have been replaced by private zero
+    /// pages, so handing out its contents would silently return wrong data.
+    fn assert_intact(&self) {
+        if self.truncated.load(Ordering::Relaxed) {
+            panic!("the memfd was truncated below the mapped range");
+        }
+    }
+}
+
+fn truncated_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "the memfd was truncated below the mapped range",
+    )
+}
+
+fn madvise(ptr: *mut libc::c_void, len: usize, advice: libc::c_int) -> io::Result<()> {
+    if unsafe { libc::madvise(ptr, len, advice) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl Drop for MmapInner {
+    fn drop(&mut self) {
+        if self.map_len > 0 {
+            unsafe {
+                libc::munmap(self.ptr, self.map_len);
+            }
+        }
+    }
+}
+
+/// A read-only shared mapping of a memfd, unmapped on drop.
+///
+/// Created by `MemFd::map` and `MemFd::map_range`.
+///
+/// Any holder of the memfd, in this or another process, can write to the mapped pages or
+/// truncate the file while the mapping exists, so the contents cannot safely be borrowed as a
+/// slice. Use `read_at` to copy them out, or `MemFd::map_sealed` for a `SealedMmap` that derefs
+/// to `[u8]`.
+pub struct Mmap(MmapInner);
+
+impl Mmap {
+    pub(crate) fn new(fd: RawFd, offset: u64, len: usize, align: usize) -> io::Result<Mmap> {
+        MmapInner::new(fd, offset, len, align, libc::PROT_READ).map(Mmap)
+    }
+
+    pub(crate) fn lock(&self, dont_fork: bool) -> io::Result<()> {
+        self.0.lock(dont_fork)
+    }
+
+    /// Returns a raw pointer to the start of the mapped range.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.0.data
+    }
+
+    /// The length of the mapped range in bytes.
+    pub fn len(&self) -> usize {
+        self.0.len
+    }
+
+    /// Whether the mapped range is empty.
+    pub fn is_empty(&self) -> bool {
+        self.0.len == 0
+    }
+
+    /// Copies bytes starting at `offset` into `buf`, returning the number of bytes copied.
+    ///
+    /// This never raises `SIGBUS`: if the memfd was truncated below the range being read, an
+    /// `UnexpectedEof` error is returned instead, both now and on every later call. The mapping
+    /// is unusable from then on; see `is_truncated`.
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read_at(offset, buf)
+    }
+
+    /// Whether `read_at` has detected that the memfd was truncated below the mapped range.
+    ///
+    /// To recover, the faulting pages were replaced by private zero pages that no longer reflect
+    /// the file. Do not access the mapping through raw pointers once this returns `true`.
+    pub fn is_truncated(&self) -> bool {
+        self.0.truncated.load(Ordering::Relaxed)
+    }
+
+    /// Borrows the mapped range as a slice.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a truncation was detected, see `is_truncated`.
+    ///
+    /// # Safety
+    ///
+    /// For as long as the slice is borrowed, nothing may write to the mapped range, through this
+    /// or any other mapping or file descriptor in any process, and the memfd must not be
+    /// truncated below it.
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        self.0.slice()
+    }
+}
+
+/// A writable shared mapping of a memfd, unmapped on drop.
+///
+/// Writes are visible to every other mapping of the same memfd. Created by `MemFd::map_mut` and
+/// `MemFd::map_range_mut`. As with `Mmap`, the contents can change or disappear at any time, so
+/// they are accessed through `read_at`, `write_at` or raw pointers.
+pub struct MmapMut(MmapInner);
+
+impl MmapMut {
+    pub(crate) fn new(fd: RawFd, offset: u64, len: usize, align: usize) -> io::Result<MmapMut> {
+        MmapInner::new(fd, offset, len, align, libc::PROT_READ | libc::PROT_WRITE).map(MmapMut)
+    }
+
+    pub(crate) fn lock(&self, dont_fork: bool) -> io::Result<()> {
+        self.0.lock(dont_fork)
+    }
+
+    /// Returns a raw pointer to the start of the mapped range.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.0.data
+    }
+
+    /// Returns a mutable raw pointer to the start of the mapped range.
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.0.data
+    }
+
+    /// The length of the mapped range in bytes.
+    pub fn len(&self) -> usize {
+        self.0.len
+    }
+
+    /// Whether the mapped range is empty.
+    pub fn is_empty(&self) -> bool {
+        self.0.len == 0
+    }
+
+    /// Copies bytes starting at `offset` into `buf` without risking `SIGBUS`.
+    ///
+    /// See `Mmap::read_at`.
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read_at(offset, buf)
+    }
+
+    /// Copies `buf` into the mapping starting at `offset`, returning the number of bytes copied.
+    ///
+    /// Like `read_at`, this fails with `UnexpectedEof` instead of raising `SIGBUS` if the memfd
+    /// was truncated below the range being written.
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_at(offset, buf)
+    }
+
+    /// Whether a truncation was detected, see `Mmap::is_truncated`.
+    pub fn is_truncated(&self) -> bool {
+        self.0.truncated.load(Ordering::Relaxed)
+    }
+
+    /// Borrows the mapped range as a slice.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a truncation was detected, see `is_truncated`.
+    ///
+    /// # Safety
+    ///
+    /// See `Mmap::as_slice`.
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        self.0.slice()
+    }
+
+    /// Borrows the mapped range as a mutable slice.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a truncation was detected, see `is_truncated`.
+    ///
+    /// # Safety
+    ///
+    /// For as long as the slice is borrowed, nothing else may read or write the mapped range,
+    /// through this or any other mapping or file descriptor in any process, and the memfd must
+    /// not be truncated below it.
+    pub unsafe fn as_mut_slice(&mut self) -> &mut [u8] {
+        self.0.assert_intact();
+        slice::from_raw_parts_mut(self.0.data, self.0.len)
+    }
+}
+
+/// A read-only mapping of a memfd sealed with `Seal::Shrink` and `Seal::Write`.
+///
+/// The seals guarantee that the mapped contents can neither change nor be truncated away, so
+/// unlike `Mmap` this derefs to `[u8]`. Created by `MemFd::map_sealed`, `VerifiedMemFd::map` and
+/// `SealedBuffer::map`.
+pub struct SealedMmap(MmapInner);
+
+impl SealedMmap {
+    /// Maps a range of `fd`, which the caller has checked to carry both seals.
+    pub(crate) fn new(fd: RawFd, offset: u64, len: usize, align: usize) -> io::Result<SealedMmap> {
+        MmapInner::new(fd, offset, len, align, libc::PROT_READ).map(SealedMmap)
+    }
+
+    pub(crate) fn lock(&self, dont_fork: bool) -> io::Result<()> {
+        self.0.lock(dont_fork)
+    }
+}
+
+impl Deref for SealedMmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.0.slice()
+    }
+}
+
+unsafe impl Send for MmapInner {}
+unsafe impl Sync for MmapInner {}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+    use std::panic;
+    use {create, Error, OpenOptions, Seal};
+
+    #[test]
+    fn map_reads_contents() {
+        let mut fd = create("mapped").unwrap();
+        fd.write_all(b"hello world").unwrap();
+
+        let map = fd.map().unwrap();
+        let mut buf = [0; 11];
+        assert_eq!(11, map.read_at(0, &mut buf).unwrap());
+        assert_eq!(b"hello world", &buf);
+        assert_eq!(b"hello world", unsafe { map.as_slice() });
+    }
+
+    #[test]
+    fn map_mut_writes_through() {
+        let fd = create("mapped").unwrap();
+        fd.set_len(4096).unwrap();
+
+        let map = fd.map_mut().unwrap();
+        assert_eq!(5, map.write_at(0, b"hello").unwrap());
+        assert_eq!(0, map.write_at(4096, b"past the end").unwrap());
+
+        let other = fd.map().unwrap();
+        let mut buf = [0; 5];
+        other.read_at(0, &mut buf).unwrap();
+        assert_eq!(b"hello", &buf);
+    }
+
+    #[test]
+    fn map_range_unaligned() {
+        let mut fd = create("mapped").unwrap();
+        fd.write_all(b"hello world").unwrap();
+
+        let map = fd.map_range(6, 5).unwrap();
+        assert_eq!(5, map.len());
+        let mut buf = [0; 8];
+        assert_eq!(5, map.read_at(0, &mut buf).unwrap());
+        assert_eq!(b"world", &buf[..5]);
+        assert!(fd.map_range(6, 6).is_err());
+    }
+
+    #[test]
+    fn map_sealed_requires_seals() {
+        let mut fd = OpenOptions::new().allow_sealing(true).create("sealed").unwrap();
+        fd.write_all(b"hello world").unwrap();
+        match fd.map_sealed() {
+            Err(Error::MissingSeals(missing)) => assert_eq!(2, missing.len()),
+            Err(other) => panic!("unexpected error: {:?}", other),
+            Ok(_) => panic!("sealed mapping of an unsealed memfd"),
+        }
+
+        fd.add_seals(&[Seal::Shrink, Seal::Write].iter().cloned().collect())
+            .unwrap();
+        assert_eq!(b"world", &fd.map_range_sealed(6, 5).unwrap()[..]);
+    }
+
+    #[test]
+    fn map_empty() {
+        let fd = create("empty").unwrap();
+        assert!(fd.map().unwrap().is_empty());
+    }
+
+    #[test]
+    fn read_at_copies_range() {
+        let mut fd = create("mapped").unwrap();
+        fd.write_all(b"hello world").unwrap();
+
+        let map = fd.map().unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(5, map.read_at(6, &mut buf).unwrap());
+        assert_eq!(b"world", &buf[..5]);
+        assert_eq!(0, map.read_at(11, &mut buf).unwrap());
+    }
+
+    #[test]
+    fn read_at_after_truncation_by_child() {
+        use libc;
+        use std::os::unix::io::AsRawFd;
+
+        let fd = create("truncated").unwrap();
+        fd.set_len(4 * 4096).unwrap();
+        let map = fd.map().unwrap();
+
+        match unsafe { libc::fork() } {
+            0 => unsafe {
+                libc::ftruncate(fd.as_raw_fd(), 0);
+                libc::_exit(0);
+            },
+            pid => {
+                assert!(pid > 0);
+                let mut status = 0;
+                unsafe { libc::waitpid(pid, &mut status, 0) };
+            }
+        }
+
+        let mut buf = [0; 8];
+        assert!(!map.is_truncated());
+        let err = map.read_at(4096, &mut buf).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+        assert!(map.is_truncated());
+        assert!(map.read_at(0, &mut buf).is_err());
+
+        let res = panic::catch_unwind(panic::AssertUnwindSafe(|| unsafe { map.as_slice().len() }));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn map_mut_refused_when_write_sealed() {
+        let fd = OpenOptions::new().allow_sealing(true).create("sealed").unwrap();
+        fd.set_len(16).unwrap();
+        fd.add_seal(Seal::Write).unwrap();
+
+        assert!(fd.map().is_ok());
+        match fd.map_mut() {
+            Err(Error::WriteSealed) => {}
+            Err(other) => panic!("unexpected error: {:?}", other),
+            Ok(_) => panic!("writable mapping of a write-sealed memfd"),
+        }
+    }
+}
@@ -0,0 +1,27 @@
+use std::fs;
+
+/// System-wide policy for executable memfds, set through the `vm.memfd_noexec` sysctl.
+///
+/// See [`memfd_create(2)`](http://man7.org/linux/man-pages/man2/memfd_create.2.html).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoexecPolicy {
+    /// `0`: memfds are executable unless `MFD_NOEXEC_SEAL` is passed.
+    Exec,
+    /// `1`: memfds are non-executable unless `MFD_EXEC` is passed.
+    NoexecSeal,
+    /// `2`: memfds are always non-executable and `MFD_EXEC` is rejected.
+    NoexecEnforced,
+}
+
+/// Reads the `vm.memfd_noexec` policy of the running kernel.
+///
+/// Returns `None` on kernels that predate `MFD_NOEXEC_SEAL` and `MFD_EXEC`.
+pub fn noexec_policy() -> Option<NoexecPolicy> {
+    let value = fs::read_to_string("/proc/sys/vm/memfd_noexec").ok()?;
+    match value.trim() {
+        "0" => Some(NoexecPolicy::Exec),
+        "1" => Some(NoexecPolicy::NoexecSeal),
+        "2" => Some(NoexecPolicy::NoexecEnforced),
+        _ => None,
+    }
+}
@@ -0,0 +1,182 @@
+use libc;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::slice;
+
+use error::Error;
+use mmap::page_size;
+use sealing::size_seals;
+use OpenOptions;
+
+/// A byte ring buffer whose memfd is mapped twice, back to back.
+///
+/// Because the second mapping mirrors the first, the readable and writable regions are always
+/// contiguous in memory, no matter where they wrap around the end of the buffer.
+///
+/// The memfd is sealed against resizing and closed once mapped, so the slices handed out can
+/// neither be truncated nor written through another mapping while they are borrowed.
+///
+/// ## Example
+///
+/// ```
+/// let mut buf = memfd::ring::MirroredBuffer::new(4096).unwrap();
+/// buf.writable()[..5].copy_from_slice(b"hello");
+/// buf.produce(5);
+/// assert_eq!(b"hello", buf.readable());
+/// buf.consume(5);
+/// ```
+pub struct MirroredBuffer {
+    base: *mut u8,
+    capacity: usize,
+    head: usize,
+    len: usize,
+}
+
+impl MirroredBuffer {
+    /// Creates a buffer holding at least `capacity` bytes, rounded up to the page size.
+    pub fn new(capacity: usize) -> Result<MirroredBuffer, Error> {
+        let page = page_size();
+        let capacity = capacity.max(1).div_ceil(page) * page;
+        let memfd = OpenOptions::new()
+            .allow_sealing(true)
+            .close_on_exec(true)
+            .create("memfd-mirrored-buffer")?;
+        memfd.set_len(capacity as u64)?;
+        memfd.add_seals(&size_seals())?;
+
+        unsafe {
+            let base = libc::mmap(
+                ptr::null_mut(),
+                2 * capacity,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            );
+            if base == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            for half in 0..2 {
+                let addr = libc::mmap(
+                    (base as *mut u8).add(half * capacity) as *mut libc::c_void,
+                    capacity,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED | libc::MAP_FIXED,
+                    memfd.as_raw_fd(),
+                    0,
+                );
+                if addr == libc::MAP_FAILED {
+                    let err = io::Error::last_os_error();
+                    libc::munmap(base, 2 * capacity);
+                    return Err(err.into());
+                }
+            }
+
+            // The mappings keep the file alive; the descriptor is closed on return.
+            Ok(MirroredBuffer {
+                base: base as *mut u8,
+                capacity,
+                head: 0,
+                len: 0,
+            })
+        }
+    }
+
+    /// The total number of bytes the buffer can hold.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of bytes available for reading.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether there are no bytes to read.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The bytes available for reading, as one contiguous slice.
+    pub fn readable(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.base.add(self.head), self.len) }
+    }
+
+    /// The free space available for writing, as one contiguous slice.
+    ///
+    /// Call `produce` to make written bytes readable.
+    pub fn writable(&mut self) -> &mut [u8] {
+        let tail = (self.head + self.len) % self.capacity;
+        unsafe { slice::from_raw_parts_mut(self.base.add(tail), self.capacity - self.len) }
+    }
+
+    /// Marks `n` bytes at the start of `writable()` as readable.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` exceeds the free space.
+    pub fn produce(&mut self, n: usize) {
+        assert!(n <= self.capacity - self.len, "produced more than the free space");
+        self.len += n;
+    }
+
+    /// Discards `n` bytes at the start of `readable()`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` exceeds the readable length.
+    pub fn consume(&mut self, n: usize) {
+        assert!(n <= self.len, "consumed more than the readable length");
+        self.head = (self.head + n) % self.capacity;
+        self.len -= n;
+    }
+}
+
+impl Drop for MirroredBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base as *mut libc::c_void, 2 * self.capacity);
+        }
+    }
+}
+
+unsafe impl Send for MirroredBuffer {}
+unsafe impl Sync for MirroredBuffer {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rounds_to_page_size() {
+        let buf = MirroredBuffer::new(1).unwrap();
+        assert_eq!(page_size(), buf.capacity());
+    }
+
+    #[test]
+    fn slices_are_contiguous_across_wrap() {
+        let mut buf = MirroredBuffer::new(page_size()).unwrap();
+        let cap = buf.capacity();
+
+        buf.produce(cap - 3);
+        buf.consume(cap - 3);
+        assert_eq!(cap, buf.writable().len());
+
+        buf.writable()[..8].copy_from_slice(b"wrapping");
+        buf.produce(8);
+        assert_eq!(b"wrapping", buf.readable());
+
+        // Both halves map the same pages, so the wrapped tail also sits at the very start.
+        let start = unsafe { slice::from_raw_parts(buf.base, 5) };
+        assert_eq!(b"pping", start);
+    }
+
+    #[test]
+    #[should_panic]
+    fn consume_past_len_panics() {
+        let mut buf = MirroredBuffer::new(1).unwrap();
+        buf.consume(1);
+    }
+}
@@ -0,0 +1,11 @@
+//! Ring buffers backed by memfds.
+//!
+//! `SpscRing` is a single-producer/single-consumer queue of byte frames that can be shared
+//! between processes by passing its memfd. `MirroredBuffer` maps one memfd twice so its
+//! contents can always be accessed as a single contiguous slice.
+
+mod mirrored;
+mod spsc;
+
+pub use self::mirrored::MirroredBuffer;
+pub use self::spsc::SpscRing;
@@ -0,0 +1,291 @@
+use std::io;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use error::Error;
+use memfd::MemFd;
+use mmap::MmapMut;
+use sealing::size_seals;
+use OpenOptions;
+
+const MAGIC: u64 = 0x6d65_6d66_6473_7073; // "memfdsps"
+const CAPACITY_OFFSET: usize = 8;
+const HEAD_OFFSET: usize = 64;
+const TAIL_OFFSET: usize = 128;
+const HEADER_SIZE: usize = 192;
+const FRAME_HEADER: usize = 4;
+
+/// A lock-free single-producer/single-consumer queue of byte frames living in a memfd.
+///
+/// The memfd holds a header with the atomic head and tail positions, followed by the data
+/// region. The other side of the queue is obtained by passing the memfd to another process and
+/// calling `SpscRing::open` there. At any time only one process may `push` and only one may
+/// `pop`; both take `&mut self`, so a single handle is never used from two threads at once.
+///
+/// The memfd is sealed against shrinking and growing, so neither side can make the other fault
+/// by truncating it.
+///
+/// ## Example
+///
+/// ```
+/// let mut ring = memfd::ring::SpscRing::create("queue", 4096).unwrap();
+/// assert!(ring.push(b"frame").unwrap());
+/// assert_eq!(Some(b"frame".to_vec()), ring.pop().unwrap());
+/// ```
+pub struct SpscRing {
+    memfd: MemFd,
+    map: MmapMut,
+    capacity: u64,
+}
+
+impl SpscRing {
+    /// Creates a ring named `name` with at least `capacity` bytes of frame storage.
+    ///
+    /// The capacity is rounded up to a power of two. Each frame occupies its length plus four
+    /// bytes.
+    pub fn create<S: Into<Vec<u8>>>(name: S, capacity: usize) -> Result<SpscRing, Error> {
+        let capacity = capacity.max(FRAME_HEADER).next_power_of_two();
+        let memfd = OpenOptions::new()
+            .allow_sealing(true)
+            .close_on_exec(true)
+            .create(name)?;
+        memfd.set_len((HEADER_SIZE + capacity) as u64)?;
+        memfd.add_seals(&size_seals())?;
+
+        let ring = SpscRing::from_parts(memfd, capacity as u64)?;
+        unsafe {
+            ptr::write(ring.map.as_ptr() as *mut u64, MAGIC);
+            ptr::write(ring.map.as_ptr().add(CAPACITY_OFFSET) as *mut u64, capacity as u64);
+        }
+        Ok(ring)
+    }
+
+    /// Opens a ring created by `SpscRing::create`, typically in another process.
+    ///
+    /// Fails with `Error::MissingSeals` unless the memfd is sealed with `Seal::Shrink` and
+    /// `Seal::Grow`.
+    pub fn open(memfd: MemFd) -> Result<SpscRing, Error> {
+        let memfd = memfd.require_seals(&size_seals())?.into_memfd();
+        let header = memfd.map_range(0, HEADER_SIZE)?;
+        let magic = unsafe { ptr::read(header.as_ptr() as *const u64) };
+        let capacity = unsafe { ptr::read(header.as_ptr().add(CAPACITY_OFFSET) as *const u64) };
+        let size = memfd.as_file().metadata()?.len();
+        if magic != MAGIC
+            || !capacity.is_power_of_two()
+            || size != HEADER_SIZE as u64 + capacity
+        {
+            return Err(invalid("memfd does not contain an SPSC ring").into());
+        }
+        SpscRing::from_parts(memfd, capacity)
+    }
+
+    fn from_parts(memfd: MemFd, capacity: u64) -> Result<SpscRing, Error> {
+        let map = memfd.map_mut()?;
+        Ok(SpscRing {
+            memfd,
+            map,
+            capacity,
+        })
+    }
+
+    /// The memfd backing this ring, to be passed to the other side.
+    pub fn memfd(&self) -> &MemFd {
+        &self.memfd
+    }
+
+    /// The number of bytes available for frames.
+    pub fn capacity(&self) -> usize {
+        self.capacity as usize
+    }
+
+    /// Appends `frame` to the ring.
+    ///
+    /// Returns `Ok(false)` if there is currently not enough free space, and an `InvalidInput`
+    /// error if the frame can never fit. Fails with `InvalidData` if the other side corrupted the
+    /// ring.
+    pub fn push(&mut self, frame: &[u8]) -> io::Result<bool> {
+        let needed = (FRAME_HEADER + frame.len()) as u64;
+        if needed > self.capacity || frame.len() > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame is larger than the ring",
+            ));
+        }
+
+        let head = self.head().load(Ordering::Acquire);
+        let tail = self.tail().load(Ordering::Relaxed);
+        let used = tail.wrapping_sub(head);
+        if used > self.capacity {
+            return Err(invalid("ring positions are corrupted"));
+        }
+        if self.capacity - used < needed {
+            return Ok(false);
+        }
+
+        self.write_at(tail, &(frame.len() as u32).to_ne_bytes());
+        self.write_at(tail.wrapping_add(FRAME_HEADER as u64), frame);
+        self.tail().store(tail.wrapping_add(needed), Ordering::Release);
+        Ok(true)
+    }
+
+    /// Removes the oldest frame from the ring.
+    ///
+    /// Returns `Ok(None)` if the ring is empty, and an `InvalidData` error if the other side
+    /// corrupted the ring.
+    pub fn pop(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let tail = self.tail().load(Ordering::Acquire);
+        let head = self.head().load(Ordering::Relaxed);
+        let used = tail.wrapping_sub(head);
+        if used == 0 {
+            return Ok(None);
+        }
+        if used < FRAME_HEADER as u64 || used > self.capacity {
+            return Err(invalid("ring positions are corrupted"));
+        }
+
+        let mut len = [0; FRAME_HEADER];
+        self.read_at(head, &mut len);
+        let len = u32::from_ne_bytes(len) as u64;
+        if len > used - FRAME_HEADER as u64 {
+            return Err(invalid("frame length exceeds the ring contents"));
+        }
+
+        let mut frame = vec![0; len as usize];
+        self.read_at(head.wrapping_add(FRAME_HEADER as u64), &mut frame);
+        self.head()
+            .store(head.wrapping_add(FRAME_HEADER as u64 + len), Ordering::Release);
+        Ok(Some(frame))
+    }
+
+    /// Whether the ring currently holds no frames.
+    pub fn is_empty(&self) -> bool {
+        self.tail().load(Ordering::Acquire) == self.head().load(Ordering::Acquire)
+    }
+
+    fn head(&self) -> &AtomicU64 {
+        unsafe { &*(self.map.as_ptr().add(HEAD_OFFSET) as *const AtomicU64) }
+    }
+
+    fn tail(&self) -> &AtomicU64 {
+        unsafe { &*(self.map.as_ptr().add(TAIL_OFFSET) as *const AtomicU64) }
+    }
+
+    fn data(&self) -> *mut u8 {
+        unsafe { self.map.as_ptr().add(HEADER_SIZE) as *mut u8 }
+    }
+
+    fn write_at(&self, pos: u64, buf: &[u8]) {
+        let start = (pos & (self.capacity - 1)) as usize;
+        let first = buf.len().min(self.capacity as usize - start);
+        unsafe {
+            ptr::copy_nonoverlapping(buf.as_ptr(), self.data().add(start), first);
+            ptr::copy_nonoverlapping(buf.as_ptr().add(first), self.data(), buf.len() - first);
+        }
+    }
+
+    fn read_at(&self, pos: u64, buf: &mut [u8]) {
+        let start = (pos & (self.capacity - 1)) as usize;
+        let first = buf.len().min(self.capacity as usize - start);
+        unsafe {
+            ptr::copy_nonoverlapping(self.data().add(start), buf.as_mut_ptr(), first);
+            ptr::copy_nonoverlapping(self.data(), buf.as_mut_ptr().add(first), buf.len() - first);
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc;
+    use std::os::unix::fs::FileExt;
+    use test_util::pass_memfd;
+
+    #[test]
+    fn push_pop_wraps_around() {
+        let mut ring = SpscRing::create("ring", 64).unwrap();
+        assert_eq!(64, ring.capacity());
+
+        for i in 0..100u32 {
+            let frame = vec![i as u8; (i % 20) as usize];
+            assert!(ring.push(&frame).unwrap());
+            assert_eq!(Some(frame), ring.pop().unwrap());
+        }
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn push_reports_full_and_oversized() {
+        let mut ring = SpscRing::create("ring", 16).unwrap();
+        assert!(ring.push(&[1; 8]).unwrap());
+        assert!(!ring.push(&[2; 8]).unwrap());
+        assert!(ring.push(&[0; 13]).is_err());
+    }
+
+    #[test]
+    fn open_rejects_other_memfds() {
+        let memfd = ::create("plain").unwrap();
+        memfd.set_len(4096).unwrap();
+        match SpscRing::open(memfd) {
+            Err(Error::MissingSeals(missing)) => assert_eq!(size_seals(), missing),
+            Err(other) => panic!("unexpected error: {:?}", other),
+            Ok(_) => panic!("opened an unsealed memfd"),
+        }
+
+        let memfd = OpenOptions::new().allow_sealing(true).create("sealed").unwrap();
+        memfd.set_len(4096).unwrap();
+        memfd.add_seals(&size_seals()).unwrap();
+        assert!(SpscRing::open(memfd).is_err());
+    }
+
+    #[test]
+    fn push_rejects_corrupted_positions() {
+        let mut ring = SpscRing::create("ring", 64).unwrap();
+        ring.memfd()
+            .as_file()
+            .write_at(&1u64.to_ne_bytes(), HEAD_OFFSET as u64)
+            .unwrap();
+
+        let err = ring.push(b"frame").unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn across_fork() {
+        const FRAMES: u32 = 10_000;
+        let mut ring = SpscRing::create("ring", 256).unwrap();
+        let mut producer = SpscRing::open(pass_memfd(ring.memfd())).unwrap();
+
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            for i in 0..FRAMES {
+                let frame = i.to_ne_bytes();
+                while !producer.push(&frame).unwrap_or(true) {
+                    unsafe { libc::sched_yield() };
+                }
+            }
+            unsafe { libc::_exit(0) };
+        }
+        assert!(pid > 0);
+
+        let mut expected = 0;
+        while expected < FRAMES {
+            match ring.pop().unwrap() {
+                Some(frame) => {
+                    assert_eq!(&expected.to_ne_bytes()[..], &frame[..]);
+                    expected += 1;
+                }
+                None => unsafe {
+                    libc::sched_yield();
+                },
+            }
+        }
+
+        let mut status = 0;
+        unsafe { libc::waitpid(pid, &mut status, 0) };
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+    }
+}
@@ -0,0 +1,259 @@
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+
+use error::Error;
+use memfd::MemFd;
+use mmap::SealedMmap;
+use sealing::{Seal, SealsHashSet};
+use OpenOptions;
+
+/// The seals applied by `SealedBufferBuilder::finish`.
+const IMMUTABLE_SEALS: [Seal; 4] = [Seal::Shrink, Seal::Grow, Seal::Write, Seal::Seal];
+
+/// An immutable memfd whose contents are guaranteed never to change.
+///
+/// The memfd is sealed with `Seal::Shrink`, `Seal::Grow`, `Seal::Write` and `Seal::Seal`, so
+/// neither this process nor any process it is shared with can modify or truncate it.
+///
+/// ## Example
+///
+/// ```
+/// let buf = memfd::SealedBuffer::from_bytes("payload", b"Hello Rust!").unwrap();
+/// assert_eq!(b"Hello Rust!", &buf.map().unwrap()[..]);
+/// ```
+#[derive(Debug)]
+pub struct SealedBuffer {
+    memfd: MemFd,
+    len: usize,
+}
+
+impl SealedBuffer {
+    /// Creates a sealed buffer named `name` holding a copy of `data`.
+    pub fn from_bytes<S: Into<Vec<u8>>>(name: S, data: &[u8]) -> Result<SealedBuffer, Error> {
+        let mut builder = SealedBufferBuilder::new(name)?;
+        builder.write_all(data)?;
+        builder.finish()
+    }
+
+    /// The size of the buffer in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Maps the buffer read-only.
+    pub fn map(&self) -> Result<SealedMmap, Error> {
+        self.memfd.map_sealed()
+    }
+
+    /// Returns a reference to the underlying memfd.
+    pub fn as_memfd(&self) -> &MemFd {
+        &self.memfd
+    }
+
+    /// Converts the buffer into the underlying memfd.
+    pub fn into_memfd(self) -> MemFd {
+        self.memfd
+    }
+}
+
+impl AsRawFd for SealedBuffer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.memfd.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for SealedBuffer {
+    fn into_raw_fd(self) -> RawFd {
+        self.memfd.into_raw_fd()
+    }
+}
+
+/// Builds a `SealedBuffer` from data written incrementally.
+///
+/// The memfd is only handed out once all seals have been applied; if building fails it is
+/// closed.
+#[derive(Debug)]
+pub struct SealedBufferBuilder {
+    memfd: MemFd,
+    len: usize,
+}
+
+impl SealedBufferBuilder {
+    /// Starts building a sealed buffer named `name`.
+    pub fn new<S: Into<Vec<u8>>>(name: S) -> Result<SealedBufferBuilder, Error> {
+        let memfd = OpenOptions::new()
+            .allow_sealing(true)
+            .close_on_exec(true)
+            .create(name)?;
+        Ok(SealedBufferBuilder { memfd, len: 0 })
+    }
+
+    /// Seals the written data and returns the immutable buffer.
+    pub fn finish(self) -> Result<SealedBuffer, Error> {
+        self.memfd.set_len(self.len as u64)?;
+        let seals: SealsHashSet = IMMUTABLE_SEALS.iter().cloned().collect();
+        self.memfd.add_seals(&seals)?;
+        Ok(SealedBuffer {
+            memfd: self.memfd,
+            len: self.len,
+        })
+    }
+}
+
+impl Write for SealedBufferBuilder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.memfd.write(buf)?;
+        self.len += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.memfd.flush()
+    }
+}
+
+/// A memfd that is known to carry `Seal::Shrink` and a required set of further seals.
+///
+/// Created by `MemFd::require_seals`. Since seals can never be removed, the guarantees they give
+/// hold for as long as this value exists: `Seal::Shrink` rules out `SIGBUS` when reading its
+/// mappings, and `Seal::Write`, which `map` requires, additionally makes their contents
+/// immutable.
+#[derive(Debug)]
+pub struct VerifiedMemFd {
+    memfd: MemFd,
+    seals: SealsHashSet,
+}
+
+impl VerifiedMemFd {
+    pub(crate) fn verify(memfd: MemFd, required: &SealsHashSet) -> Result<VerifiedMemFd, Error> {
+        let seals = memfd.seals()?;
+        let mut required = required.clone();
+        required.insert(Seal::Shrink);
+        let missing: SealsHashSet = required.difference(&seals).cloned().collect();
+        if !missing.is_empty() {
+            return Err(Error::MissingSeals(missing));
+        }
+        Ok(VerifiedMemFd { memfd, seals })
+    }
+
+    /// The seals present on the memfd when it was verified, a superset of the required ones.
+    pub fn seals(&self) -> &SealsHashSet {
+        &self.seals
+    }
+
+    /// Maps the whole memfd read-only.
+    ///
+    /// Fails with `Error::MissingSeals` unless `Seal::Write` was among the verified seals, see
+    /// `MemFd::map_sealed`.
+    pub fn map(&self) -> Result<SealedMmap, Error> {
+        self.memfd.map_sealed()
+    }
+
+    /// Maps `len` bytes starting at `offset` read-only, see `map`.
+    pub fn map_range(&self, offset: u64, len: usize) -> Result<SealedMmap, Error> {
+        self.memfd.map_range_sealed(offset, len)
+    }
+
+    /// Returns a reference to the underlying memfd.
+    pub fn as_memfd(&self) -> &MemFd {
+        &self.memfd
+    }
+
+    /// Converts this value into the underlying memfd.
+    pub fn into_memfd(self) -> MemFd {
+        self.memfd
+    }
+}
+
+impl AsRawFd for VerifiedMemFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.memfd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_bytes_is_sealed() {
+        let buf = SealedBuffer::from_bytes("sealed", b"hello world").unwrap();
+        assert_eq!(11, buf.len());
+        assert_eq!(b"hello world", &buf.map().unwrap()[..]);
+
+        let seals = buf.as_memfd().seals().unwrap();
+        for seal in IMMUTABLE_SEALS.iter() {
+            assert!(seals.contains(seal));
+        }
+        assert!(buf.as_memfd().set_len(0).is_err());
+        assert!(buf.as_memfd().map_mut().is_err());
+    }
+
+    #[test]
+    fn builder_writes_incrementally() {
+        let mut builder = SealedBufferBuilder::new("built").unwrap();
+        builder.write_all(b"hello ").unwrap();
+        builder.write_all(b"world").unwrap();
+
+        let buf = builder.finish().unwrap();
+        assert_eq!(b"hello world", &buf.map().unwrap()[..]);
+    }
+
+    #[test]
+    fn require_seals_accepts_sealed_buffer() {
+        let buf = SealedBuffer::from_bytes("sealed", b"hello").unwrap();
+        let required: SealsHashSet = [Seal::Shrink, Seal::Write].iter().cloned().collect();
+
+        let verified = buf.into_memfd().require_seals(&required).unwrap();
+        assert!(verified.seals().is_superset(&required));
+        assert_eq!(b"hello", &verified.map().unwrap()[..]);
+    }
+
+    #[test]
+    fn require_seals_lists_missing() {
+        let memfd = OpenOptions::new().allow_sealing(true).create("partial").unwrap();
+        memfd.add_seal(Seal::Shrink).unwrap();
+        let required: SealsHashSet = [Seal::Shrink, Seal::Write].iter().cloned().collect();
+
+        match memfd.require_seals(&required) {
+            Err(Error::MissingSeals(missing)) => {
+                assert_eq!(1, missing.len());
+                assert!(missing.contains(&Seal::Write));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn require_seals_always_requires_shrink() {
+        let memfd = OpenOptions::new().allow_sealing(true).create("unsealed").unwrap();
+        match memfd.require_seals(&SealsHashSet::new()) {
+            Err(Error::MissingSeals(missing)) => {
+                assert_eq!(1, missing.len());
+                assert!(missing.contains(&Seal::Shrink));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let memfd = OpenOptions::new().allow_sealing(true).create("shrink").unwrap();
+        memfd.add_seal(Seal::Shrink).unwrap();
+        let verified = memfd.require_seals(&SealsHashSet::new()).unwrap();
+        match verified.map() {
+            Err(Error::MissingSeals(missing)) => assert!(missing.contains(&Seal::Write)),
+            Err(other) => panic!("unexpected error: {:?}", other),
+            Ok(_) => panic!("sealed mapping of a writable memfd"),
+        }
+    }
+
+    #[test]
+    fn empty_buffer() {
+        let buf = SealedBuffer::from_bytes("empty", b"").unwrap();
+        assert!(buf.is_empty());
+        assert!(buf.map().unwrap().is_empty());
+    }
+}
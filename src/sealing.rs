@@ -0,0 +1,190 @@
+use libc;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use error::Error;
+
+/// A seal that restricts the operations allowed on a memfd.
+///
+/// See [`fcntl(2)`](http://man7.org/linux/man-pages/man2/fcntl.2.html) for the exact semantics
+/// of each seal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Seal {
+    /// `F_SEAL_SEAL`: the set of seals itself cannot be changed anymore.
+    Seal,
+    /// `F_SEAL_SHRINK`: the file size cannot be reduced.
+    Shrink,
+    /// `F_SEAL_GROW`: the file size cannot be increased.
+    Grow,
+    /// `F_SEAL_WRITE`: the file contents cannot be modified.
+    Write,
+    /// `F_SEAL_FUTURE_WRITE`: the file contents cannot be modified through new writable
+    /// mappings or `write(2)`, while existing shared writable mappings keep working.
+    FutureWrite,
+    /// `F_SEAL_EXEC`: the executable permission bits cannot be changed. Set by
+    /// `OpenOptions::noexec_seal`.
+    Exec,
+}
+
+/// A set of seals, as returned by `seals()`.
+pub type SealsHashSet = HashSet<Seal>;
+
+const ALL_SEALS: [Seal; 6] = [
+    Seal::Seal,
+    Seal::Shrink,
+    Seal::Grow,
+    Seal::Write,
+    Seal::FutureWrite,
+    Seal::Exec,
+];
+
+impl Seal {
+    /// The raw `F_SEAL_*` value of this seal.
+    pub fn bits(self) -> libc::c_int {
+        match self {
+            Seal::Seal => libc::F_SEAL_SEAL,
+            Seal::Shrink => libc::F_SEAL_SHRINK,
+            Seal::Grow => libc::F_SEAL_GROW,
+            Seal::Write => libc::F_SEAL_WRITE,
+            Seal::FutureWrite => libc::F_SEAL_FUTURE_WRITE,
+            Seal::Exec => libc::F_SEAL_EXEC,
+        }
+    }
+}
+
+pub fn seals_to_bits<'a, I: IntoIterator<Item = &'a Seal>>(seals: I) -> libc::c_int {
+    seals.into_iter().fold(0, |bits, seal| bits | seal.bits())
+}
+
+pub fn bits_to_seals(bits: libc::c_int) -> SealsHashSet {
+    ALL_SEALS
+        .iter()
+        .cloned()
+        .filter(|seal| bits & seal.bits() != 0)
+        .collect()
+}
+
+/// The seals that fix the size of a memfd, so peers mapping it can never fault on truncation.
+pub(crate) fn size_seals() -> SealsHashSet {
+    [Seal::Shrink, Seal::Grow].iter().cloned().collect()
+}
+
+/// Reads the seals currently set on `fd`.
+pub fn get_seals(fd: RawFd) -> io::Result<SealsHashSet> {
+    let res = unsafe { libc::fcntl(fd, libc::F_GET_SEALS) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(bits_to_seals(res))
+}
+
+/// Adds the seals in `bits` to `fd`.
+///
+/// `sealing_allowed` tells whether the memfd is known to have been created with
+/// `MFD_ALLOW_SEALING`. When unknown, a memfd whose only seal is `F_SEAL_SEAL` is assumed to
+/// have been created without it, since that is the state the kernel leaves such files in.
+pub fn add_seals(fd: RawFd, bits: libc::c_int, sealing_allowed: Option<bool>) -> Result<(), Error> {
+    let res = unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, bits) };
+    if res == 0 {
+        return Ok(());
+    }
+
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() != Some(libc::EPERM) {
+        return Err(Error::Io(err));
+    }
+
+    let allowed = match sealing_allowed {
+        Some(allowed) => allowed,
+        None => {
+            let current = get_seals(fd)?;
+            !(current.len() == 1 && current.contains(&Seal::Seal))
+        }
+    };
+    if allowed {
+        Err(Error::SealLocked)
+    } else {
+        Err(Error::SealingNotAllowed)
+    }
+}
+
+/// Seal operations on memfd file handles.
+pub trait SealExt {
+    /// Adds a single seal.
+    fn add_seal(&self, seal: Seal) -> Result<(), Error>;
+
+    /// Adds all seals in `seals` in a single operation.
+    fn add_seals(&self, seals: &SealsHashSet) -> Result<(), Error>;
+
+    /// Returns the seals currently set.
+    fn seals(&self) -> Result<SealsHashSet, Error>;
+}
+
+impl SealExt for File {
+    fn add_seal(&self, seal: Seal) -> Result<(), Error> {
+        add_seals(self.as_raw_fd(), seal.bits(), None)
+    }
+
+    fn add_seals(&self, seals: &SealsHashSet) -> Result<(), Error> {
+        add_seals(self.as_raw_fd(), seals_to_bits(seals), None)
+    }
+
+    fn seals(&self) -> Result<SealsHashSet, Error> {
+        Ok(get_seals(self.as_raw_fd())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use OpenOptions;
+
+    #[test]
+    fn bits_roundtrip() {
+        let seals: SealsHashSet = ALL_SEALS.iter().cloned().collect();
+        assert_eq!(seals, bits_to_seals(seals_to_bits(&seals)));
+        assert!(bits_to_seals(0).is_empty());
+    }
+
+    #[test]
+    fn add_and_query_seals() {
+        let fd = OpenOptions::new().allow_sealing(true).create("sealed").unwrap();
+        assert!(fd.seals().unwrap().is_empty());
+
+        let mut seals = SealsHashSet::new();
+        seals.insert(Seal::Shrink);
+        seals.insert(Seal::Grow);
+        fd.add_seals(&seals).unwrap();
+        assert_eq!(seals, fd.seals().unwrap());
+    }
+
+    #[test]
+    fn write_seal_prevents_writes() {
+        let mut fd = OpenOptions::new().allow_sealing(true).create("sealed").unwrap();
+        fd.add_seal(Seal::Write).unwrap();
+        assert!(fd.write(b"hello").is_err());
+    }
+
+    #[test]
+    fn sealing_not_allowed() {
+        let fd = OpenOptions::new().create("unsealable").unwrap();
+        match fd.as_file().add_seal(Seal::Shrink) {
+            Err(Error::SealingNotAllowed) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn seal_locked() {
+        let fd = OpenOptions::new().allow_sealing(true).create("locked").unwrap();
+        fd.add_seal(Seal::Grow).unwrap();
+        fd.add_seal(Seal::Seal).unwrap();
+        match fd.as_file().add_seal(Seal::Shrink) {
+            Err(Error::SealLocked) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
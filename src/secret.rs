@@ -0,0 +1,223 @@
+//! Secret memory backed by `memfd_secret(2)`.
+//!
+//! Pages of a secret memfd are removed from the kernel's direct map, so they are only accessible
+//! through mappings of the file in processes holding the descriptor. The kernel does not support
+//! `read(2)` or `write(2)` on such files; the contents can only be accessed through `SecretMap`.
+//!
+//! Secret memory is available since Linux 5.14. Kernels before 6.5 additionally need to be
+//! booted with `secretmem.enable=1`; `memfd::features().memfd_secret()` reports whether it can
+//! be used.
+//!
+//! ## Example
+//!
+//! ```
+//! use memfd::secret::SecretMemFd;
+//!
+//! let mut fd = match SecretMemFd::new() {
+//!     Ok(fd) => fd,
+//!     Err(memfd::Error::SecretMemUnavailable) => return,
+//!     Err(err) => panic!("{}", err),
+//! };
+//! fd.set_len(4096).unwrap();
+//! let mut key = fd.map().unwrap();
+//! key[..4].copy_from_slice(b"key!");
+//! // The contents are zeroized when `fd` is dropped.
+//! ```
+
+use libc;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::ptr;
+
+use error::Error;
+use mmap::{self, MmapMut};
+
+/// A file descriptor created by `memfd_secret(2)`.
+///
+/// Unlike `MemFd` this type does not implement `Read` or `Write`, as the kernel rejects those
+/// calls on secret memory. Use `map` to access the contents.
+///
+/// The contents are zeroized when the `SecretMemFd` is dropped, even if the descriptor was
+/// duplicated or passed to another process. Use `into_raw_fd` to keep them.
+#[derive(Debug)]
+pub struct SecretMemFd {
+    file: ManuallyDrop<File>,
+}
+
+impl SecretMemFd {
+    /// Creates a new, empty secret memfd with the close-on-exec flag set.
+    ///
+    /// Fails with `Error::SecretMemUnavailable` if the kernel was built without
+    /// `CONFIG_SECRETMEM` or booted without `secretmem.enable=1`.
+    pub fn new() -> Result<SecretMemFd, Error> {
+        let fd = unsafe { libc::syscall(libc::SYS_memfd_secret, libc::O_CLOEXEC) };
+        if fd < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ENOSYS) {
+                return Err(Error::SecretMemUnavailable);
+            }
+            return Err(err.into());
+        }
+        Ok(SecretMemFd {
+            file: ManuallyDrop::new(unsafe { File::from_raw_fd(fd as libc::c_int) }),
+        })
+    }
+
+    /// Sets the size of the secret memfd to `size` bytes.
+    ///
+    /// This can only be done once: the kernel refuses to resize a secret memfd that already has
+    /// a size and fails with `EINVAL`.
+    pub fn set_len(&self, size: u64) -> io::Result<()> {
+        self.file.set_len(size)
+    }
+
+    /// The size of the secret memfd in bytes.
+    pub fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    /// Whether the secret memfd is empty.
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Maps the whole secret memfd read-write.
+    ///
+    /// The mapping borrows the `SecretMemFd` mutably, so only one can be live at a time.
+    /// Secret memory is locked and counts against `RLIMIT_MEMLOCK`, so the mapping fails with
+    /// `EAGAIN` or `ENOMEM` once the limit is exhausted.
+    pub fn map(&mut self) -> Result<SecretMap<'_>, Error> {
+        let len = self.len()? as usize;
+        let map = MmapMut::new(self.as_raw_fd(), 0, len, mmap::page_size())?;
+        Ok(SecretMap {
+            map,
+            marker: PhantomData,
+        })
+    }
+
+    /// Overwrites the whole secret memfd with zeros.
+    fn zeroize(&mut self) -> Result<(), Error> {
+        let mut map = self.map()?;
+        let ptr = map.map.as_mut_ptr();
+        for i in 0..map.map.len() {
+            // Volatile writes are never elided, even though the mapping is about to go away.
+            unsafe { ptr::write_volatile(ptr.add(i), 0) };
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SecretMemFd {
+    fn drop(&mut self) {
+        // Best effort: the kernel frees the pages once the last reference is gone either way.
+        let _ = self.zeroize();
+        unsafe { ManuallyDrop::drop(&mut self.file) };
+    }
+}
+
+impl AsRawFd for SecretMemFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for SecretMemFd {
+    /// Releases the descriptor without zeroizing the contents.
+    fn into_raw_fd(self) -> RawFd {
+        let mut fd = ManuallyDrop::new(self);
+        unsafe { ManuallyDrop::take(&mut fd.file) }.into_raw_fd()
+    }
+}
+
+/// A writable mapping of a `SecretMemFd`.
+///
+/// The pages are zeroized when the `SecretMemFd` the mapping borrows is dropped.
+pub struct SecretMap<'a> {
+    map: MmapMut,
+    marker: PhantomData<&'a mut SecretMemFd>,
+}
+
+impl<'a> Deref for SecretMap<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { self.map.as_slice() }
+    }
+}
+
+impl<'a> DerefMut for SecretMap<'a> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { self.map.as_mut_slice() }
+    }
+}
+
+impl<'a> fmt::Debug for SecretMap<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never print the secret itself.
+        f.debug_struct("SecretMap")
+            .field("len", &self.map.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use features;
+
+    fn secret() -> Option<SecretMemFd> {
+        match SecretMemFd::new() {
+            Ok(fd) => Some(fd),
+            Err(Error::SecretMemUnavailable) => {
+                assert!(!features().memfd_secret());
+                None
+            }
+            Err(err) => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn zeroizes_on_drop() {
+        let mut fd = match secret() {
+            Some(fd) => fd,
+            None => return,
+        };
+        fd.set_len(mmap::page_size() as u64).unwrap();
+
+        let mut map = fd.map().unwrap();
+        map[..6].copy_from_slice(b"secret");
+        assert!(!format!("{:?}", map).contains("secret"));
+        drop(map);
+        assert_eq!(b"secret", &fd.map().unwrap()[..6]);
+
+        let len = mmap::page_size();
+        let copy = unsafe { File::from_raw_fd(libc::dup(fd.as_raw_fd())) };
+        drop(fd);
+        let map = MmapMut::new(copy.as_raw_fd(), 0, len, len).unwrap();
+        let mut data = vec![0xff; len];
+        map.read_at(0, &mut data).unwrap();
+        assert!(data.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn refuses_read_and_write() {
+        let fd = match secret() {
+            Some(fd) => fd,
+            None => return,
+        };
+        fd.set_len(mmap::page_size() as u64).unwrap();
+        let err = fd.set_len(2 * mmap::page_size() as u64).unwrap_err();
+        assert_eq!(Some(libc::EINVAL), err.raw_os_error());
+
+        let mut buf = [0; 8];
+        let res = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr() as *mut _, buf.len()) };
+        assert!(res < 0);
+        let res = unsafe { libc::write(fd.as_raw_fd(), buf.as_ptr() as *const _, buf.len()) };
+        assert!(res < 0);
+    }
+}
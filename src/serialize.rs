@@ -0,0 +1,186 @@
+use bincode::{self, Options};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+
+use error::Error;
+use memfd::MemFd;
+use sealed::{SealedBuffer, SealedBufferBuilder};
+use sealing::Seal;
+use OpenOptions;
+
+const MAGIC: [u8; 4] = *b"MFDS";
+const FORMAT_BINCODE: u32 = 1;
+const HEADER_SIZE: usize = 16;
+
+/// Serializes `value` into a new memfd named `name`.
+///
+/// The memfd starts with a 16 byte header: the magic `MFDS`, a little-endian `u32` format tag
+/// and the little-endian `u64` length of the payload that follows. The payload is encoded with
+/// bincode and streamed straight into the file. Sealing is allowed on the memfd; use
+/// `to_sealed_memfd` to make it immutable right away.
+///
+/// ## Example
+///
+/// ```
+/// let fd = memfd::to_memfd(&vec![1u32, 2, 3], "config").unwrap();
+/// assert_eq!(vec![1u32, 2, 3], memfd::from_memfd::<Vec<u32>>(&fd).unwrap());
+/// ```
+pub fn to_memfd<T, S>(value: &T, name: S) -> Result<MemFd, Error>
+where
+    T: Serialize + ?Sized,
+    S: Into<Vec<u8>>,
+{
+    let mut memfd = OpenOptions::new()
+        .allow_sealing(true)
+        .close_on_exec(true)
+        .create(name)?;
+    write_value(&mut memfd, value)?;
+    memfd.seek(SeekFrom::Start(0))?;
+    Ok(memfd)
+}
+
+/// Serializes `value` into a sealed, immutable buffer named `name`.
+///
+/// See `to_memfd` for the layout. Receivers can rely on the seals to read the value without
+/// copying it out of the file first.
+pub fn to_sealed_memfd<T, S>(value: &T, name: S) -> Result<SealedBuffer, Error>
+where
+    T: Serialize + ?Sized,
+    S: Into<Vec<u8>>,
+{
+    let mut builder = SealedBufferBuilder::new(name)?;
+    write_value(&mut builder, value)?;
+    builder.finish()
+}
+
+/// Deserializes a value written by `to_memfd` or `to_sealed_memfd`.
+///
+/// If the memfd is sealed against shrinking and writing, the value is decoded directly from a
+/// read-only mapping; otherwise the payload is read into memory first. Fails with `InvalidData` if the
+/// header is missing, names an unknown format, or the payload is truncated or malformed.
+pub fn from_memfd<T: DeserializeOwned>(memfd: &MemFd) -> Result<T, Error> {
+    let seals = memfd.seals()?;
+    if seals.contains(&Seal::Shrink) && seals.contains(&Seal::Write) {
+        let map = memfd.map_sealed()?;
+        return decode(&map);
+    }
+
+    let len = memfd.as_file().metadata()?.len() as usize;
+    let mut data = vec![0; len];
+    memfd.as_file().read_exact_at(&mut data, 0)?;
+    decode(&data)
+}
+
+fn write_value<W: Write, T: Serialize + ?Sized>(writer: W, value: &T) -> Result<(), Error> {
+    let len = bincode::DefaultOptions::new()
+        .serialized_size(value)
+        .map_err(|err| bincode_error(*err))?;
+
+    let mut writer = BufWriter::new(writer);
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&FORMAT_BINCODE.to_le_bytes())?;
+    writer.write_all(&len.to_le_bytes())?;
+    bincode::DefaultOptions::new()
+        .serialize_into(&mut writer, value)
+        .map_err(|err| bincode_error(*err))?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, Error> {
+    if data.len() < HEADER_SIZE || data[..4] != MAGIC {
+        return Err(invalid("memfd does not contain a serialized value"));
+    }
+    let mut tag = [0; 4];
+    tag.copy_from_slice(&data[4..8]);
+    if u32::from_le_bytes(tag) != FORMAT_BINCODE {
+        return Err(invalid("unsupported serialization format"));
+    }
+    let mut len = [0; 8];
+    len.copy_from_slice(&data[8..16]);
+    let len = u64::from_le_bytes(len);
+    if len > (data.len() - HEADER_SIZE) as u64 {
+        return Err(invalid("serialized value is truncated"));
+    }
+
+    // The limit keeps a malformed payload from allocating more than it could possibly hold.
+    bincode::DefaultOptions::new()
+        .with_limit(len)
+        .deserialize(&data[HEADER_SIZE..HEADER_SIZE + len as usize])
+        .map_err(|err| bincode_error(*err))
+}
+
+fn bincode_error(err: bincode::ErrorKind) -> Error {
+    match err {
+        bincode::ErrorKind::Io(err) => Error::Io(err),
+        err => io::Error::new(io::ErrorKind::InvalidData, err).into(),
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::HashMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Model {
+        name: String,
+        weights: Vec<f32>,
+        labels: HashMap<u32, String>,
+    }
+
+    fn model() -> Model {
+        Model {
+            name: "tiny".to_string(),
+            weights: (0..1000).map(|i| i as f32 * 0.5).collect(),
+            labels: vec![(1, "cat".to_string()), (2, "dog".to_string())]
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let fd = to_memfd(&model(), "model").unwrap();
+        assert_eq!(model(), from_memfd::<Model>(&fd).unwrap());
+    }
+
+    #[test]
+    fn sealed_roundtrip() {
+        let buf = to_sealed_memfd(&model(), "model").unwrap();
+        assert!(buf.as_memfd().seals().unwrap().contains(&Seal::Write));
+        assert_eq!(model(), from_memfd::<Model>(buf.as_memfd()).unwrap());
+    }
+
+    #[test]
+    fn header_layout() {
+        let buf = to_sealed_memfd(&7u8, "byte").unwrap();
+        let map = buf.map().unwrap();
+        assert_eq!(b"MFDS", &map[..4]);
+        assert_eq!(&FORMAT_BINCODE.to_le_bytes(), &map[4..8]);
+        assert_eq!(&1u64.to_le_bytes(), &map[8..16]);
+        assert_eq!(&[7], &map[16..]);
+    }
+
+    #[test]
+    fn rejects_foreign_and_truncated_data() {
+        let mut fd = ::create("garbage").unwrap();
+        fd.write_all(b"not a serialized value").unwrap();
+        assert!(from_memfd::<u32>(&fd).is_err());
+
+        let fd = to_memfd(&model(), "model").unwrap();
+        let len = fd.as_file().metadata().unwrap().len();
+        fd.set_len(len - 1).unwrap();
+        match from_memfd::<Model>(&fd) {
+            Err(Error::Io(err)) => assert_eq!(io::ErrorKind::InvalidData, err.kind()),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,380 @@
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::ptr;
+
+use error::Error;
+use memfd::MemFd;
+use mmap::{page_size, MmapMut};
+use sealing::{Seal, SealsHashSet};
+use OpenOptions;
+
+/// Types that can be shared between processes as raw bytes.
+///
+/// # Safety
+///
+/// Implementors must be `repr(C)` or `repr(transparent)` and valid for every bit pattern, so
+/// that any contents another process writes into the memfd are a valid value. This rules out
+/// references, pointers, `bool`, `char`, enums and types with padding.
+pub unsafe trait Pod: Copy + 'static {}
+
+macro_rules! impl_pod {
+    ($($ty:ty)*) => {
+        $(unsafe impl Pod for $ty {})*
+    };
+}
+
+impl_pod!(u8 u16 u32 u64 u128 usize i8 i16 i32 i64 i128 isize f32 f64);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// A single `T` in a memfd of exactly its size.
+///
+/// The memfd is sealed against shrinking and growing, so it can be passed to another process,
+/// which reconstructs the box with `SharedBox::from_memfd`. Both sides see each other's writes;
+/// synchronize concurrent access, e.g. with `memfd::sync`.
+///
+/// As another process may change the value at any time, no references to it are handed out.
+/// It is copied in and out with volatile reads and writes instead.
+///
+/// ## Example
+///
+/// ```
+/// let mut counter = memfd::SharedBox::new("counter", 0u64).unwrap();
+/// let value = counter.read();
+/// counter.write(value + 1);
+/// assert_eq!(1, counter.read());
+/// ```
+pub struct SharedBox<T: Pod> {
+    memfd: MemFd,
+    map: MmapMut,
+    marker: PhantomData<T>,
+}
+
+impl<T: Pod> SharedBox<T> {
+    /// Creates a memfd named `name` holding `value`.
+    pub fn new<S: Into<Vec<u8>>>(name: S, value: T) -> Result<SharedBox<T>, Error> {
+        check_type::<T>()?;
+        let memfd = create_sized(name, mem::size_of::<T>())?;
+        let mut map = memfd.map_mut()?;
+        unsafe { ptr::write(map.as_mut_ptr() as *mut T, value) };
+        Ok(SharedBox {
+            memfd,
+            map,
+            marker: PhantomData,
+        })
+    }
+
+    /// Reconstructs a box from a memfd, typically received from another process.
+    ///
+    /// Fails with `Error::MissingSeals` if the memfd is not sealed against shrinking, and with
+    /// `InvalidData` if its size differs from the size of `T`.
+    pub fn from_memfd(memfd: MemFd) -> Result<SharedBox<T>, Error> {
+        check_type::<T>()?;
+        let map = map_checked(&memfd)?;
+        if map.len() != mem::size_of::<T>() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "memfd size does not match the size of the type",
+            )
+            .into());
+        }
+        Ok(SharedBox {
+            memfd,
+            map,
+            marker: PhantomData,
+        })
+    }
+
+    /// Returns a copy of the current value.
+    pub fn read(&self) -> T {
+        unsafe { ptr::read_volatile(self.map.as_ptr() as *const T) }
+    }
+
+    /// Overwrites the value.
+    pub fn write(&mut self, value: T) {
+        unsafe { ptr::write_volatile(self.map.as_mut_ptr() as *mut T, value) }
+    }
+
+    /// Returns the underlying memfd.
+    pub fn as_memfd(&self) -> &MemFd {
+        &self.memfd
+    }
+
+    /// Unmaps the value and returns the underlying memfd.
+    pub fn into_memfd(self) -> MemFd {
+        self.memfd
+    }
+}
+
+impl<T: Pod + fmt::Debug> fmt::Debug for SharedBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("SharedBox").field(&self.read()).finish()
+    }
+}
+
+impl<T: Pod> AsRawFd for SharedBox<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.memfd.as_raw_fd()
+    }
+}
+
+impl<T: Pod> IntoRawFd for SharedBox<T> {
+    fn into_raw_fd(self) -> RawFd {
+        self.memfd.into_raw_fd()
+    }
+}
+
+/// A fixed-length slice of `T` in a memfd, see `SharedBox`.
+///
+/// ## Example
+///
+/// ```
+/// let mut samples = memfd::SharedSlice::<f32>::zeroed("samples", 1024).unwrap();
+/// samples.write(0, 0.5);
+/// assert_eq!(0.5, samples.read(0));
+/// assert_eq!(1024, samples.len());
+/// ```
+pub struct SharedSlice<T: Pod> {
+    memfd: MemFd,
+    map: MmapMut,
+    len: usize,
+    marker: PhantomData<T>,
+}
+
+impl<T: Pod> SharedSlice<T> {
+    /// Creates a memfd named `name` holding `len` zeroed elements.
+    pub fn zeroed<S: Into<Vec<u8>>>(name: S, len: usize) -> Result<SharedSlice<T>, Error> {
+        check_type::<T>()?;
+        let size = len
+            .checked_mul(mem::size_of::<T>())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "slice length overflows"))?;
+        let memfd = create_sized(name, size)?;
+        let map = memfd.map_mut()?;
+        Ok(SharedSlice {
+            memfd,
+            map,
+            len,
+            marker: PhantomData,
+        })
+    }
+
+    /// Creates a memfd named `name` holding a copy of `data`.
+    pub fn from_slice<S: Into<Vec<u8>>>(name: S, data: &[T]) -> Result<SharedSlice<T>, Error> {
+        let mut slice = SharedSlice::zeroed(name, data.len())?;
+        slice.copy_from_slice(data);
+        Ok(slice)
+    }
+
+    /// Reconstructs a slice from a memfd, typically received from another process.
+    ///
+    /// Fails with `Error::MissingSeals` if the memfd is not sealed against shrinking, and with
+    /// `InvalidData` if its size is not a multiple of the size of `T`.
+    pub fn from_memfd(memfd: MemFd) -> Result<SharedSlice<T>, Error> {
+        check_type::<T>()?;
+        let map = map_checked(&memfd)?;
+        if !map.len().is_multiple_of(mem::size_of::<T>()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "memfd size is not a multiple of the size of the type",
+            )
+            .into());
+        }
+        Ok(SharedSlice {
+            len: map.len() / mem::size_of::<T>(),
+            memfd,
+            map,
+            marker: PhantomData,
+        })
+    }
+
+    /// The number of elements.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the slice has no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns a copy of the element at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn read(&self, index: usize) -> T {
+        assert!(index < self.len, "index out of bounds");
+        unsafe { ptr::read_volatile((self.map.as_ptr() as *const T).add(index)) }
+    }
+
+    /// Overwrites the element at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn write(&mut self, index: usize, value: T) {
+        assert!(index < self.len, "index out of bounds");
+        unsafe { ptr::write_volatile((self.map.as_mut_ptr() as *mut T).add(index), value) }
+    }
+
+    /// Copies all elements into a vector.
+    pub fn to_vec(&self) -> Vec<T> {
+        (0..self.len).map(|i| self.read(i)).collect()
+    }
+
+    /// Overwrites all elements with those of `src`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `src` has a different length.
+    pub fn copy_from_slice(&mut self, src: &[T]) {
+        assert_eq!(self.len, src.len(), "source slice length does not match");
+        for (i, &value) in src.iter().enumerate() {
+            self.write(i, value);
+        }
+    }
+
+    /// Returns the underlying memfd.
+    pub fn as_memfd(&self) -> &MemFd {
+        &self.memfd
+    }
+
+    /// Unmaps the slice and returns the underlying memfd.
+    pub fn into_memfd(self) -> MemFd {
+        self.memfd
+    }
+}
+
+impl<T: Pod + fmt::Debug> fmt::Debug for SharedSlice<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("SharedSlice").field(&self.to_vec()).finish()
+    }
+}
+
+impl<T: Pod> AsRawFd for SharedSlice<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.memfd.as_raw_fd()
+    }
+}
+
+impl<T: Pod> IntoRawFd for SharedSlice<T> {
+    fn into_raw_fd(self) -> RawFd {
+        self.memfd.into_raw_fd()
+    }
+}
+
+/// Rejects types that cannot be placed at the start of a mapping.
+fn check_type<T>() -> io::Result<()> {
+    if mem::size_of::<T>() == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "zero-sized types cannot be shared",
+        ));
+    }
+    if mem::align_of::<T>() > page_size() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "type alignment exceeds the page size",
+        ));
+    }
+    Ok(())
+}
+
+fn create_sized<S: Into<Vec<u8>>>(name: S, size: usize) -> Result<MemFd, Error> {
+    let memfd = OpenOptions::new()
+        .allow_sealing(true)
+        .close_on_exec(true)
+        .create(name)?;
+    memfd.set_len(size as u64)?;
+    memfd.add_seals(&[Seal::Shrink, Seal::Grow].iter().cloned().collect())?;
+    Ok(memfd)
+}
+
+/// Maps a received memfd after checking it cannot be truncated under the mapping.
+fn map_checked(memfd: &MemFd) -> Result<MmapMut, Error> {
+    let seals = memfd.seals()?;
+    if !seals.contains(&Seal::Shrink) {
+        let missing: SealsHashSet = [Seal::Shrink].iter().cloned().collect();
+        return Err(Error::MissingSeals(missing));
+    }
+    memfd.map_mut()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc;
+    use test_util::pass_memfd;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[repr(C)]
+    struct Point {
+        x: f64,
+        y: f64,
+    }
+
+    unsafe impl Pod for Point {}
+
+    #[test]
+    fn box_is_sized_and_sealed() {
+        let shared = SharedBox::new("point", Point { x: 1.0, y: 2.0 }).unwrap();
+        assert_eq!(16, shared.as_memfd().as_file().metadata().unwrap().len());
+        assert!(shared.as_memfd().seals().unwrap().contains(&Seal::Grow));
+        assert_eq!(Point { x: 1.0, y: 2.0 }, shared.read());
+    }
+
+    #[test]
+    fn box_shared_across_fork() {
+        let shared = SharedBox::new("counter", [0u32; 4]).unwrap();
+        let mut other = SharedBox::<[u32; 4]>::from_memfd(pass_memfd(shared.as_memfd())).unwrap();
+
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            other.write([0, 0, 42, 0]);
+            unsafe { libc::_exit(0) };
+        }
+        let mut status = 0;
+        unsafe { libc::waitpid(pid, &mut status, 0) };
+        assert_eq!([0, 0, 42, 0], shared.read());
+    }
+
+    #[test]
+    fn from_memfd_validates_size_and_seals() {
+        let shared = SharedBox::new("value", 7u64).unwrap();
+        assert!(SharedBox::<u32>::from_memfd(pass_memfd(shared.as_memfd())).is_err());
+        assert!(SharedSlice::<[u8; 3]>::from_memfd(pass_memfd(shared.as_memfd())).is_err());
+        assert_eq!(
+            vec![7u32, 0],
+            SharedSlice::<u32>::from_memfd(pass_memfd(shared.as_memfd()))
+                .unwrap()
+                .to_vec()
+        );
+
+        let unsealed = OpenOptions::new().create("unsealed").unwrap();
+        unsealed.set_len(8).unwrap();
+        match SharedBox::<u64>::from_memfd(unsealed) {
+            Err(Error::MissingSeals(missing)) => assert!(missing.contains(&Seal::Shrink)),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn slice_roundtrip() {
+        let mut slice = SharedSlice::from_slice("slice", &[1i16, 2, 3]).unwrap();
+        slice.write(1, -2);
+        let other = SharedSlice::<i16>::from_memfd(pass_memfd(slice.as_memfd())).unwrap();
+        assert_eq!(vec![1, -2, 3], other.to_vec());
+        assert_eq!(-2, other.read(1));
+
+        let empty = SharedSlice::<u64>::zeroed("empty", 0).unwrap();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn rejects_zero_sized_types() {
+        assert!(SharedBox::new("unit", [0u8; 0]).is_err());
+    }
+}
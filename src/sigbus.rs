@@ -0,0 +1,173 @@
+//! Recovery from `SIGBUS` raised while reading a mapping whose file was truncated.
+//!
+//! A process-wide handler is installed on first use. When a fault hits the range guarded by the
+//! current thread, the faulting page is replaced by anonymous zeroed memory so the access can
+//! complete, and the fault is recorded for the guarded operation to report. Any other `SIGBUS`
+//! is handled as the previously installed disposition would have handled it.
+
+use libc;
+use std::cell::Cell;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{compiler_fence, AtomicPtr, Ordering};
+use std::sync::Once;
+
+static INSTALL: Once = Once::new();
+static PREVIOUS: AtomicPtr<libc::sigaction> = AtomicPtr::new(ptr::null_mut());
+
+thread_local! {
+    /// Start, length and page size of the range guarded by this thread.
+    static GUARD: Cell<(usize, usize, usize)> = const { Cell::new((0, 0, 0)) };
+    static FAULTED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `f` with faults in the `len` bytes at `start` converted into a `false` return value.
+///
+/// `page_size` is the page granularity of the mapping containing the range.
+pub fn guarded<F: FnOnce()>(start: *const u8, len: usize, page_size: usize, f: F) -> bool {
+    install();
+
+    GUARD.with(|guard| guard.set((start as usize, len, page_size)));
+    FAULTED.with(|faulted| faulted.set(false));
+    compiler_fence(Ordering::SeqCst);
+
+    f();
+
+    compiler_fence(Ordering::SeqCst);
+    GUARD.with(|guard| guard.set((0, 0, 0)));
+    !FAULTED.with(|faulted| faulted.get())
+}
+
+fn install() {
+    INSTALL.call_once(|| unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handle_sigbus as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_NODEFER;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        // Record the previous disposition before replacing it, so a signal arriving in between
+        // is never handled without it.
+        let mut previous: libc::sigaction = mem::zeroed();
+        if libc::sigaction(libc::SIGBUS, ptr::null(), &mut previous) == 0 {
+            PREVIOUS.store(Box::into_raw(Box::new(previous)), Ordering::SeqCst);
+        }
+        libc::sigaction(libc::SIGBUS, &action, ptr::null_mut());
+    });
+}
+
+extern "C" fn handle_sigbus(sig: libc::c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    let addr = unsafe { (*info).si_addr() } as usize;
+    let (start, len, page_size) = GUARD
+        .try_with(|guard| guard.get())
+        .unwrap_or((0, 0, 0));
+
+    if len > 0 && addr >= start && addr < start + len {
+        let page = addr & !(page_size - 1);
+        let res = unsafe {
+            libc::mmap(
+                page as *mut libc::c_void,
+                page_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_FIXED | libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if res != libc::MAP_FAILED {
+            let _ = FAULTED.try_with(|faulted| faulted.set(true));
+            return;
+        }
+    }
+
+    unsafe { forward(sig, info, ctx) }
+}
+
+unsafe fn forward(sig: libc::c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    let previous = PREVIOUS.load(Ordering::SeqCst);
+    let handler = if previous.is_null() {
+        libc::SIG_DFL
+    } else {
+        (*previous).sa_sigaction
+    };
+    // Sent with kill(2) and friends rather than raised by a faulting access.
+    let sent = (*info).si_code <= 0;
+
+    if handler == libc::SIG_IGN && sent {
+        return;
+    }
+    if handler == libc::SIG_DFL || handler == libc::SIG_IGN {
+        // The default action terminates the process, and the kernel applies it to faults even
+        // when the signal is ignored. Restore it and either re-raise a sent signal, or return to
+        // re-execute the faulting access.
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = libc::SIG_DFL;
+        libc::sigaction(sig, &action, ptr::null_mut());
+        if sent {
+            libc::raise(sig);
+        }
+    } else if (*previous).sa_flags & libc::SA_SIGINFO != 0 {
+        let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+            mem::transmute(handler);
+        handler(sig, info, ctx);
+    } else {
+        let handler: extern "C" fn(libc::c_int) = mem::transmute(handler);
+        handler(sig);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status_of<F: FnOnce()>(child: F) -> libc::c_int {
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            let limit = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) };
+            child();
+            unsafe { libc::_exit(0) };
+        }
+        assert!(pid > 0);
+        let mut status = 0;
+        unsafe { libc::waitpid(pid, &mut status, 0) };
+        status
+    }
+
+    /// Installs the handler on top of `disposition` in a forked child.
+    unsafe fn install_over(disposition: libc::sighandler_t) {
+        install();
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = disposition;
+        *PREVIOUS.load(Ordering::SeqCst) = action;
+    }
+
+    #[test]
+    fn sent_sigbus_keeps_default_action() {
+        let status = status_of(|| unsafe {
+            install_over(libc::SIG_DFL);
+            libc::raise(libc::SIGBUS);
+        });
+        assert!(libc::WIFSIGNALED(status));
+        assert_eq!(libc::SIGBUS, libc::WTERMSIG(status));
+    }
+
+    #[test]
+    fn sent_sigbus_stays_ignored() {
+        let status = status_of(|| unsafe {
+            install_over(libc::SIG_IGN);
+            libc::raise(libc::SIGBUS);
+            libc::raise(libc::SIGBUS);
+            // Our handler must still be in place.
+            let mut current: libc::sigaction = mem::zeroed();
+            libc::sigaction(libc::SIGBUS, ptr::null(), &mut current);
+            if current.sa_sigaction != handle_sigbus as *const () as libc::sighandler_t {
+                libc::_exit(1);
+            }
+        });
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(0, libc::WEXITSTATUS(status));
+    }
+}
@@ -0,0 +1,324 @@
+use libc;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+
+use memfd::MemFd;
+
+/// Maximum number of file descriptors the kernel passes in one message (`SCM_MAX_FD`).
+pub const MAX_FDS: usize = 253;
+
+/// Sends `memfd` together with `payload` over `stream`.
+///
+/// The receiving side obtains both with `recv_memfd`.
+pub fn send_memfd(stream: &UnixStream, memfd: &MemFd, payload: &[u8]) -> io::Result<()> {
+    send_memfds(stream, &[memfd], payload)
+}
+
+/// Sends up to `MAX_FDS` memfds together with `payload` over `stream` in a single message.
+///
+/// The receiving side obtains them with `recv_memfds`.
+pub fn send_memfds(stream: &UnixStream, memfds: &[&MemFd], payload: &[u8]) -> io::Result<()> {
+    let header = message_header(memfds, payload)?;
+    let fds: Vec<RawFd> = memfds.iter().map(|memfd| memfd.as_raw_fd()).collect();
+    let sent = send_with_fds(stream.as_raw_fd(), &header, payload, &fds)?;
+
+    // The descriptors travel with the first byte; the rest is plain stream data.
+    let mut stream = stream;
+    if sent < header.len() {
+        stream.write_all(&header[sent..])?;
+        stream.write_all(payload)
+    } else {
+        stream.write_all(&payload[sent - header.len()..])
+    }
+}
+
+/// Receives a single memfd and its payload sent with `send_memfd`.
+///
+/// Fails if the message carries no descriptor or one that is not a memfd, or if its payload is
+/// longer than `max_payload` bytes. Any additional descriptors in the message are closed.
+pub fn recv_memfd(stream: &UnixStream, max_payload: usize) -> io::Result<(MemFd, Vec<u8>)> {
+    let (memfds, payload) = recv_memfds(stream, max_payload)?;
+    Ok((first_memfd(memfds)?, payload))
+}
+
+/// Receives the memfds and payload sent with `send_memfds`.
+///
+/// Fails with `ErrorKind::InvalidData` if any received descriptor is not a memfd, or if the
+/// payload is longer than `max_payload` bytes; all received descriptors are closed in that case.
+/// The rejected payload is read and discarded, so the next message can still be received from
+/// `stream`.
+pub fn recv_memfds(stream: &UnixStream, max_payload: usize) -> io::Result<(Vec<MemFd>, Vec<u8>)> {
+    let mut header = [0; 4];
+    let (read, fds) = recv_with_fds(stream.as_raw_fd(), &mut header)?;
+    let memfds = into_memfds(fds);
+
+    let mut stream = stream;
+    stream.read_exact(&mut header[read..])?;
+    let len = u32::from_ne_bytes(header) as usize;
+    let checked = memfds.and_then(|memfds| {
+        check_payload_len(len, max_payload)?;
+        Ok(memfds)
+    });
+    let memfds = match checked {
+        Ok(memfds) => memfds,
+        Err(err) => {
+            io::copy(&mut stream.take(len as u64), &mut io::sink())?;
+            return Err(err);
+        }
+    };
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload)?;
+    Ok((memfds, payload))
+}
+
+/// Checks that a message can be sent and returns its length header.
+pub(crate) fn message_header(memfds: &[&MemFd], payload: &[u8]) -> io::Result<[u8; 4]> {
+    if memfds.len() > MAX_FDS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "too many memfds for a single message",
+        ));
+    }
+    if payload.len() > u32::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "payload too large"));
+    }
+    Ok((payload.len() as u32).to_ne_bytes())
+}
+
+/// Checks the payload length announced by a received header against the caller's limit.
+pub(crate) fn check_payload_len(len: usize, max_payload: usize) -> io::Result<()> {
+    if len > max_payload {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("payload of {} bytes exceeds the maximum of {}", len, max_payload),
+        ));
+    }
+    Ok(())
+}
+
+/// Takes ownership of received descriptors, closing all of them unless every one is a memfd.
+pub(crate) fn into_memfds(fds: Vec<RawFd>) -> io::Result<Vec<MemFd>> {
+    let mut memfds = Vec::with_capacity(fds.len());
+    let mut invalid = false;
+    for fd in fds {
+        let file = unsafe { File::from_raw_fd(fd) };
+        match MemFd::try_from_file(file) {
+            Ok(memfd) => memfds.push(memfd),
+            Err(_) => invalid = true,
+        }
+    }
+    if invalid {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "received a file descriptor that is not a memfd",
+        ));
+    }
+    Ok(memfds)
+}
+
+/// Keeps the first memfd of a message, closing the others.
+pub(crate) fn first_memfd(memfds: Vec<MemFd>) -> io::Result<MemFd> {
+    memfds.into_iter().next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "message did not carry a memfd")
+    })
+}
+
+pub(crate) fn send_with_fds(sock: RawFd, header: &[u8], payload: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+    let mut iov = [
+        libc::iovec {
+            iov_base: header.as_ptr() as *mut libc::c_void,
+            iov_len: header.len(),
+        },
+        libc::iovec {
+            iov_base: payload.as_ptr() as *mut libc::c_void,
+            iov_len: payload.len(),
+        },
+    ];
+    let fds_len = mem::size_of_val(fds) as libc::c_uint;
+    let mut cmsg_buf = vec![0u64; cmsg_space(fds_len) / 8 + 1];
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = iov.as_mut_ptr();
+    msg.msg_iovlen = iov.len() as _;
+    if !fds.is_empty() {
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = cmsg_space(fds_len) as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+            ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+        }
+    }
+
+    loop {
+        let res = unsafe { libc::sendmsg(sock, &msg, libc::MSG_NOSIGNAL) };
+        if res >= 0 {
+            return Ok(res as usize);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+pub(crate) fn recv_with_fds(sock: RawFd, buf: &mut [u8]) -> io::Result<(usize, Vec<RawFd>)> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let space = cmsg_space((MAX_FDS * mem::size_of::<RawFd>()) as libc::c_uint);
+    let mut cmsg_buf = vec![0u64; space / 8 + 1];
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+
+    let read = loop {
+        let res = unsafe { libc::recvmsg(sock, &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if res >= 0 {
+            break res as usize;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    };
+
+    let mut fds = Vec::new();
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data_len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let count = data_len / mem::size_of::<RawFd>();
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                for i in 0..count {
+                    fds.push(ptr::read_unaligned(data.add(i)));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    if read == 0 {
+        close_all(&fds);
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed before a message was received",
+        ));
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        close_all(&fds);
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "file descriptors were truncated",
+        ));
+    }
+    Ok((read, fds))
+}
+
+fn cmsg_space(len: libc::c_uint) -> usize {
+    unsafe { libc::CMSG_SPACE(len) as usize }
+}
+
+fn close_all(fds: &[RawFd]) {
+    for &fd in fds {
+        unsafe {
+            libc::close(fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use create;
+
+    #[test]
+    fn send_and_receive() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut memfd = create("passed").unwrap();
+        memfd.write_all(b"shared").unwrap();
+
+        send_memfd(&a, &memfd, b"payload").unwrap();
+        let (received, payload) = recv_memfd(&b, 64).unwrap();
+        assert_eq!(b"payload", &payload[..]);
+        let mut data = [0; 6];
+        received.map().unwrap().read_at(0, &mut data).unwrap();
+        assert_eq!(b"shared", &data);
+    }
+
+    #[test]
+    fn send_multiple() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let first = create("first").unwrap();
+        let second = create("second").unwrap();
+        second.set_len(42).unwrap();
+
+        send_memfds(&a, &[&first, &second], b"").unwrap();
+        send_memfd(&a, &first, b"next").unwrap();
+
+        let (memfds, payload) = recv_memfds(&b, 64).unwrap();
+        assert!(payload.is_empty());
+        assert_eq!(2, memfds.len());
+        assert_eq!(42, memfds[1].as_file().metadata().unwrap().len());
+
+        let (_, payload) = recv_memfd(&b, 64).unwrap();
+        assert_eq!(b"next", &payload[..]);
+    }
+
+    #[test]
+    fn recv_memfd_closes_extra_fds() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let first = create("first").unwrap();
+        let second = create("second").unwrap();
+
+        send_memfds(&a, &[&first, &second], b"").unwrap();
+        let (memfd, _) = recv_memfd(&b, 64).unwrap();
+        assert_eq!(Some(&b"first"[..]), memfd.name().map(|name| name.to_bytes()));
+    }
+
+    #[test]
+    fn rejects_non_memfd() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let (c, _d) = UnixStream::pair().unwrap();
+
+        send_with_fds(a.as_raw_fd(), &4u32.to_ne_bytes(), b"junk", &[c.as_raw_fd()]).unwrap();
+        send_memfd(&a, &create("next").unwrap(), b"next").unwrap();
+        let err = recv_memfd(&b, 64).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        let (_, payload) = recv_memfd(&b, 64).unwrap();
+        assert_eq!(b"next", &payload[..]);
+    }
+
+    #[test]
+    fn rejects_oversized_payload() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let memfd = create("passed").unwrap();
+
+        send_memfd(&a, &memfd, &[7; 65]).unwrap();
+        send_memfd(&a, &memfd, b"next").unwrap();
+        let err = recv_memfd(&b, 64).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        let (_, payload) = recv_memfd(&b, 64).unwrap();
+        assert_eq!(b"next", &payload[..]);
+    }
+
+    #[test]
+    fn recv_without_fd() {
+        let (mut a, b) = UnixStream::pair().unwrap();
+        a.write_all(&0u32.to_ne_bytes()).unwrap();
+        assert!(recv_memfd(&b, 64).is_err());
+    }
+}
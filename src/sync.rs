@@ -0,0 +1,580 @@
+//! Cross-process synchronization primitives living inside memfd mappings.
+//!
+//! Each primitive is a `#[repr(C)]` structure that is placed at an offset inside a shared
+//! `MmapMut`. `SharedMutex` is a robust, process-shared pthread mutex and has to be initialized
+//! once with `SharedMutex::init`. The other primitives are 32-bit futex words for which all-zero
+//! memory, as found in a freshly extended memfd, is a valid initial state: the event is unset and
+//! the semaphore count is zero. Blocking uses `FUTEX_WAIT`/`FUTEX_WAKE` without
+//! `FUTEX_PRIVATE_FLAG`, so every process mapping the memfd can wait on and wake the same
+//! primitive.
+//!
+//! ## Example
+//!
+//! ```
+//! use memfd::sync::SharedMutex;
+//! let memfd = memfd::create("locks").unwrap();
+//! memfd.set_len(4096).unwrap();
+//! let map = memfd.map_mut().unwrap();
+//!
+//! let mutex = unsafe { SharedMutex::init(&map, 0) }.unwrap();
+//! let guard = mutex.lock().unwrap();
+//! drop(guard);
+//! ```
+
+use libc;
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use futex;
+use mmap::MmapMut;
+
+/// Returns a reference to the `T` placed at `offset` in `map`.
+fn place<T>(map: &MmapMut, offset: usize) -> io::Result<&T> {
+    let end = offset.checked_add(mem::size_of::<T>());
+    if end.is_none_or(|end| end > map.len()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "offset lies outside the mapping",
+        ));
+    }
+    let ptr = unsafe { map.as_ptr().add(offset) };
+    if !(ptr as usize).is_multiple_of(mem::align_of::<T>()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "offset is not suitably aligned",
+        ));
+    }
+    Ok(unsafe { &*(ptr as *const T) })
+}
+
+/// Remaining time until `deadline`, or `None` if it has passed.
+fn remaining(deadline: Option<Instant>) -> Option<Option<Duration>> {
+    match deadline {
+        None => Some(None),
+        Some(deadline) => {
+            let now = Instant::now();
+            if now >= deadline {
+                None
+            } else {
+                Some(Some(deadline - now))
+            }
+        }
+    }
+}
+
+/// A robust mutual exclusion lock shared between processes.
+///
+/// This is a `pthread_mutex_t` initialized with `PTHREAD_PROCESS_SHARED` and
+/// `PTHREAD_MUTEX_ROBUST`, so it has to be set up once with `SharedMutex::init` before any
+/// process locks it. If its owner exits while holding the lock, the kernel releases it and the
+/// next locker, including one that is already blocked, gets `OwnerDied` so the protected data
+/// can be checked for consistency.
+#[repr(C)]
+pub struct SharedMutex {
+    inner: UnsafeCell<libc::pthread_mutex_t>,
+}
+
+unsafe impl Send for SharedMutex {}
+unsafe impl Sync for SharedMutex {}
+
+impl SharedMutex {
+    /// Initializes a robust, process-shared mutex at `offset` in `map` and returns it.
+    ///
+    /// # Safety
+    ///
+    /// No thread in any process may be using a mutex at that location, e.g. by holding or
+    /// waiting for it.
+    pub unsafe fn init(map: &MmapMut, offset: usize) -> io::Result<&SharedMutex> {
+        let mutex: &SharedMutex = place(map, offset)?;
+        let mut attr = mem::MaybeUninit::<libc::pthread_mutexattr_t>::uninit();
+        cvt(libc::pthread_mutexattr_init(attr.as_mut_ptr()))?;
+        let res = cvt(libc::pthread_mutexattr_setpshared(
+            attr.as_mut_ptr(),
+            libc::PTHREAD_PROCESS_SHARED,
+        ))
+        .and_then(|_| {
+            cvt(libc::pthread_mutexattr_setrobust(
+                attr.as_mut_ptr(),
+                libc::PTHREAD_MUTEX_ROBUST,
+            ))
+        })
+        .and_then(|_| cvt(libc::pthread_mutex_init(mutex.inner.get(), attr.as_ptr())));
+        libc::pthread_mutexattr_destroy(attr.as_mut_ptr());
+        res.map(|_| mutex)
+    }
+
+    /// Returns the mutex placed at `offset` in `map`.
+    ///
+    /// The mutex must have been initialized with `SharedMutex::init`, possibly by another
+    /// process. Otherwise the all-zero memory is an ordinary mutex that neither detects dead
+    /// owners nor reliably wakes waiters in other processes.
+    pub fn at(map: &MmapMut, offset: usize) -> io::Result<&SharedMutex> {
+        place(map, offset)
+    }
+
+    /// Acquires the lock, blocking until it is available.
+    pub fn lock(&self) -> Result<SharedMutexGuard<'_>, OwnerDied<'_>> {
+        let res = unsafe { libc::pthread_mutex_lock(self.inner.get()) };
+        match self.acquired(res, "pthread_mutex_lock") {
+            Some(res) => res,
+            None => unreachable!("pthread_mutex_lock returned without the lock"),
+        }
+    }
+
+    /// Acquires the lock, blocking for at most `timeout`.
+    ///
+    /// Returns `None` if the timeout expired.
+    pub fn lock_timeout(&self, timeout: Duration) -> Option<Result<SharedMutexGuard<'_>, OwnerDied<'_>>> {
+        let deadline = realtime_deadline(timeout);
+        let res = unsafe { libc::pthread_mutex_timedlock(self.inner.get(), &deadline) };
+        self.acquired(res, "pthread_mutex_timedlock")
+    }
+
+    /// Acquires the lock if it is available without blocking.
+    pub fn try_lock(&self) -> Option<Result<SharedMutexGuard<'_>, OwnerDied<'_>>> {
+        let res = unsafe { libc::pthread_mutex_trylock(self.inner.get()) };
+        self.acquired(res, "pthread_mutex_trylock")
+    }
+
+    /// Turns the result of a locking call into a guard, or `None` if the lock is not held.
+    ///
+    /// A mutex whose owner died is marked consistent right away, so it stays usable even if the
+    /// caller does not repair the protected data.
+    fn acquired(&self, res: libc::c_int, call: &str) -> Option<Result<SharedMutexGuard<'_>, OwnerDied<'_>>> {
+        let guard = || SharedMutexGuard {
+            mutex: self,
+            marker: PhantomData,
+        };
+        match res {
+            0 => Some(Ok(guard())),
+            libc::EOWNERDEAD => {
+                unsafe { libc::pthread_mutex_consistent(self.inner.get()) };
+                Some(Err(OwnerDied(guard())))
+            }
+            libc::EBUSY | libc::ETIMEDOUT => None,
+            err => panic!("{} failed: {}", call, io::Error::from_raw_os_error(err)),
+        }
+    }
+
+    fn unlock(&self) {
+        unsafe { libc::pthread_mutex_unlock(self.inner.get()) };
+    }
+}
+
+impl fmt::Debug for SharedMutex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedMutex").finish_non_exhaustive()
+    }
+}
+
+/// Converts the return value of a pthread call.
+fn cvt(res: libc::c_int) -> io::Result<()> {
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(res))
+    }
+}
+
+/// The absolute `CLOCK_REALTIME` time `timeout` from now, as expected by
+/// `pthread_mutex_timedlock`.
+fn realtime_deadline(timeout: Duration) -> libc::timespec {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut now) };
+    let nanos = now.tv_nsec as u64 + u64::from(timeout.subsec_nanos());
+    let secs = timeout.as_secs().min(libc::time_t::MAX as u64) as libc::time_t;
+    libc::timespec {
+        tv_sec: now
+            .tv_sec
+            .saturating_add(secs)
+            .saturating_add((nanos / 1_000_000_000) as libc::time_t),
+        tv_nsec: (nanos % 1_000_000_000) as _,
+    }
+}
+
+/// Holds a `SharedMutex` locked; the lock is released on drop.
+///
+/// The guard cannot be sent to another thread, as only the locking thread may unlock a robust
+/// mutex.
+#[derive(Debug)]
+pub struct SharedMutexGuard<'a> {
+    mutex: &'a SharedMutex,
+    marker: PhantomData<*const ()>,
+}
+
+impl<'a> Drop for SharedMutexGuard<'a> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// The previous owner of a `SharedMutex` exited while holding it.
+///
+/// The lock has nevertheless been acquired; the guard is available through `into_guard`.
+#[derive(Debug)]
+pub struct OwnerDied<'a>(SharedMutexGuard<'a>);
+
+impl<'a> OwnerDied<'a> {
+    /// Returns the guard of the acquired lock.
+    pub fn into_guard(self) -> SharedMutexGuard<'a> {
+        self.0
+    }
+}
+
+impl<'a> fmt::Display for OwnerDied<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the previous owner of the mutex died while holding it")
+    }
+}
+
+/// A condition variable shared between processes, used together with a `SharedMutex`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SharedCondvar {
+    seq: AtomicU32,
+}
+
+impl SharedCondvar {
+    /// Returns the condition variable placed at `offset` in `map`.
+    pub fn at(map: &MmapMut, offset: usize) -> io::Result<&SharedCondvar> {
+        place(map, offset)
+    }
+
+    /// Releases the lock held by `guard`, waits for a notification and re-acquires the lock.
+    ///
+    /// Spurious wake-ups are possible, so the awaited condition has to be re-checked.
+    pub fn wait<'a>(
+        &self,
+        guard: SharedMutexGuard<'a>,
+    ) -> Result<SharedMutexGuard<'a>, OwnerDied<'a>> {
+        let mutex = guard.mutex;
+        let seq = self.seq.load(Ordering::Relaxed);
+        drop(guard);
+        futex::wait(&self.seq, seq, None);
+        mutex.lock()
+    }
+
+    /// Like `wait`, but waits at most `timeout`.
+    ///
+    /// The returned flag is `false` if the timeout expired.
+    pub fn wait_timeout<'a>(
+        &self,
+        guard: SharedMutexGuard<'a>,
+        timeout: Duration,
+    ) -> Result<(SharedMutexGuard<'a>, bool), OwnerDied<'a>> {
+        let mutex = guard.mutex;
+        let seq = self.seq.load(Ordering::Relaxed);
+        drop(guard);
+        let notified = futex::wait(&self.seq, seq, Some(timeout));
+        mutex.lock().map(|guard| (guard, notified))
+    }
+
+    /// Wakes one waiting thread.
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        futex::wake(&self.seq, 1);
+    }
+
+    /// Wakes all waiting threads.
+    pub fn notify_all(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        futex::wake_all(&self.seq);
+    }
+}
+
+/// A manual-reset event shared between processes.
+///
+/// Once set, all current and future waiters pass until the event is reset.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SharedEvent {
+    state: AtomicU32,
+}
+
+impl SharedEvent {
+    /// Returns the event placed at `offset` in `map`.
+    pub fn at(map: &MmapMut, offset: usize) -> io::Result<&SharedEvent> {
+        place(map, offset)
+    }
+
+    /// Sets the event and wakes all waiters.
+    pub fn set(&self) {
+        if self.state.swap(1, Ordering::Release) == 0 {
+            futex::wake_all(&self.state);
+        }
+    }
+
+    /// Resets the event.
+    pub fn reset(&self) {
+        self.state.store(0, Ordering::Release);
+    }
+
+    /// Whether the event is set.
+    pub fn is_set(&self) -> bool {
+        self.state.load(Ordering::Acquire) != 0
+    }
+
+    /// Blocks until the event is set.
+    pub fn wait(&self) {
+        while !self.is_set() {
+            futex::wait(&self.state, 0, None);
+        }
+    }
+
+    /// Blocks until the event is set or `timeout` expires, returning whether it is set.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Some(Instant::now() + timeout);
+        while !self.is_set() {
+            match remaining(deadline) {
+                Some(wait) => {
+                    futex::wait(&self.state, 0, wait);
+                }
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+/// A counting semaphore shared between processes.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SharedSemaphore {
+    count: AtomicU32,
+}
+
+impl SharedSemaphore {
+    /// Returns the semaphore placed at `offset` in `map`.
+    pub fn at(map: &MmapMut, offset: usize) -> io::Result<&SharedSemaphore> {
+        place(map, offset)
+    }
+
+    /// The current count.
+    pub fn count(&self) -> u32 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Increments the count by `n` and wakes up to `n` waiters.
+    pub fn post(&self, n: u32) {
+        self.count.fetch_add(n, Ordering::Release);
+        futex::wake(&self.count, n.min(i32::MAX as u32) as i32);
+    }
+
+    /// Decrements the count if it is positive, without blocking.
+    pub fn try_wait(&self) -> bool {
+        let mut count = self.count.load(Ordering::Relaxed);
+        while count > 0 {
+            match self.count.compare_exchange_weak(
+                count,
+                count - 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(current) => count = current,
+            }
+        }
+        false
+    }
+
+    /// Blocks until the count is positive, then decrements it.
+    pub fn wait(&self) {
+        while !self.try_wait() {
+            futex::wait(&self.count, 0, None);
+        }
+    }
+
+    /// Like `wait`, but blocks at most `timeout`. Returns whether the count was decremented.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Some(Instant::now() + timeout);
+        while !self.try_wait() {
+            match remaining(deadline) {
+                Some(wait) => {
+                    futex::wait(&self.count, 0, wait);
+                }
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use create;
+    use std::ptr;
+    use std::thread;
+
+    fn shared_map() -> MmapMut {
+        let memfd = create("sync").unwrap();
+        memfd.set_len(4096).unwrap();
+        memfd.map_mut().unwrap()
+    }
+
+    fn fork<F: FnOnce() -> bool>(child: F) -> libc::pid_t {
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            let code = if child() { 0 } else { 1 };
+            unsafe { libc::_exit(code) };
+        }
+        assert!(pid > 0);
+        pid
+    }
+
+    fn join(pid: libc::pid_t) {
+        let mut status = 0;
+        unsafe { libc::waitpid(pid, &mut status, 0) };
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+    }
+
+    #[test]
+    fn placement_is_checked() {
+        let map = shared_map();
+        let size = mem::size_of::<SharedMutex>();
+        assert!(SharedMutex::at(&map, 2).is_err());
+        assert!(SharedMutex::at(&map, 4096 - size + 8).is_err());
+        assert!(SharedMutex::at(&map, 4096 - size).is_ok());
+    }
+
+    #[test]
+    fn mutex_excludes_threads() {
+        let map = shared_map();
+        unsafe { SharedMutex::init(&map, 0) }.unwrap();
+        let addr = map.as_ptr() as usize;
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                thread::spawn(move || {
+                    let mutex = unsafe { &*(addr as *const SharedMutex) };
+                    let counter = (addr + 64) as *mut u64;
+                    for _ in 0..1000 {
+                        let _guard = mutex.lock().unwrap();
+                        unsafe { ptr::write_volatile(counter, ptr::read_volatile(counter) + 1) };
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(4000, unsafe { ptr::read((addr + 64) as *const u64) });
+    }
+
+    #[test]
+    fn mutex_owner_died() {
+        let map = shared_map();
+        let mutex = unsafe { SharedMutex::init(&map, 0) }.unwrap();
+
+        join(fork(|| {
+            mem::forget(mutex.lock());
+            true
+        }));
+
+        assert!(mutex.lock().is_err());
+        assert!(mutex.try_lock().unwrap().is_ok());
+    }
+
+    #[test]
+    fn mutex_owner_died_wakes_waiter() {
+        let map = shared_map();
+        let mutex = unsafe { SharedMutex::init(&map, 0) }.unwrap();
+        let locked = SharedEvent::at(&map, 64).unwrap();
+
+        let pid = fork(|| {
+            mem::forget(mutex.lock());
+            locked.set();
+            thread::sleep(Duration::from_millis(50));
+            true
+        });
+        locked.wait();
+        assert!(mutex.lock().is_err());
+        join(pid);
+    }
+
+    #[test]
+    fn try_lock_and_timeout() {
+        let map = shared_map();
+        let mutex = unsafe { SharedMutex::init(&map, 0) }.unwrap();
+        let _guard = mutex.lock().unwrap();
+
+        let blocked = thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let timed_out = mutex.lock_timeout(Duration::from_millis(20)).is_none();
+                    mutex.try_lock().is_none() && timed_out
+                })
+                .join()
+                .unwrap()
+        });
+        assert!(blocked);
+    }
+
+    #[test]
+    fn condvar_across_fork() {
+        let map = shared_map();
+        let mutex = unsafe { SharedMutex::init(&map, 0) }.unwrap();
+        let condvar = SharedCondvar::at(&map, 64).unwrap();
+        let flag = unsafe { map.as_ptr().add(68) as *mut u32 };
+
+        let pid = fork(|| {
+            let _guard = mutex.lock().unwrap();
+            unsafe { ptr::write_volatile(flag, 1) };
+            condvar.notify_all();
+            true
+        });
+
+        let mut guard = mutex.lock().unwrap();
+        while unsafe { ptr::read_volatile(flag) } == 0 {
+            guard = condvar
+                .wait_timeout(guard, Duration::from_millis(100))
+                .unwrap()
+                .0;
+        }
+        drop(guard);
+        join(pid);
+    }
+
+    #[test]
+    fn event_across_fork() {
+        let map = shared_map();
+        let event = SharedEvent::at(&map, 0).unwrap();
+        assert!(!event.wait_timeout(Duration::from_millis(1)));
+
+        let pid = fork(|| {
+            event.set();
+            true
+        });
+        event.wait();
+        assert!(event.is_set());
+        event.reset();
+        assert!(!event.is_set());
+        join(pid);
+    }
+
+    #[test]
+    fn semaphore_across_fork() {
+        let map = shared_map();
+        let semaphore = SharedSemaphore::at(&map, 0).unwrap();
+        assert!(!semaphore.try_wait());
+
+        let pid = fork(|| {
+            semaphore.post(3);
+            true
+        });
+        for _ in 0..3 {
+            semaphore.wait();
+        }
+        assert!(!semaphore.wait_timeout(Duration::from_millis(1)));
+        assert_eq!(0, semaphore.count());
+        join(pid);
+    }
+}
@@ -0,0 +1,13 @@
+//! Helpers shared by the unit tests.
+
+use std::os::unix::net::UnixStream;
+
+use memfd::MemFd;
+use socket;
+
+/// Passes `memfd` over a socket pair, yielding the descriptor another process would receive.
+pub fn pass_memfd(memfd: &MemFd) -> MemFd {
+    let (a, b) = UnixStream::pair().unwrap();
+    socket::send_memfd(&a, memfd, b"").unwrap();
+    socket::recv_memfd(&b, 0).unwrap().0
+}